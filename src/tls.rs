@@ -1,3 +1,4 @@
 pub mod acme;
 pub mod grades;
 pub mod load;
+pub mod upstream;
@@ -1,6 +1,5 @@
 use log::{info, warn};
-use pingora::tls::ssl::{select_next_proto, AlpnError, SslRef, SslVersion};
-use pingora_core::listeners::tls::TlsSettings;
+use pingora::tls::ssl::{select_next_proto, AlpnError, SslAcceptorBuilder, SslRef, SslVersion};
 
 #[derive(Debug)]
 pub struct CipherSuite {
@@ -38,7 +37,8 @@ pub fn prefer_h2<'a>(_ssl: &mut SslRef, alpn_in: &'a [u8]) -> Result<&'a [u8], A
     }
 }
 
-pub fn set_tsl_grade(tls_settings: &mut TlsSettings, grade: &str) {
+// Takes the builder, so it applies both to pingora TlsSettings and to acceptors of PROXY protocol listeners
+pub fn set_tsl_grade(tls_settings: &mut SslAcceptorBuilder, grade: &str) {
     let config_grade = TlsGrade::from_str(grade);
     match config_grade {
         Some(TlsGrade::High) => {
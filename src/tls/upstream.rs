@@ -0,0 +1,68 @@
+use crate::utils::lazylock::{UPSTREAM_CA, UPSTREAM_CERTS};
+use crate::utils::structs::UpstreamTls;
+use log::{error, info};
+use pingora::tls::pkey::PKey;
+use pingora::tls::x509::X509;
+use pingora_core::upstreams::peer::HttpPeer;
+use pingora_core::utils::tls::CertKey;
+use std::fs;
+use std::sync::Arc;
+
+// Reads CA bundle and client certificate of an upstream into memory, called on every config load so rotated files are picked up.
+pub fn load_upstream_tls(tls: &UpstreamTls) {
+    if let Some(ca_file) = &tls.ca_file {
+        match fs::read(ca_file.as_ref())
+            .map_err(|e| e.to_string())
+            .and_then(|pem| X509::stack_from_pem(&pem).map_err(|e| e.to_string()))
+        {
+            Ok(certs) if !certs.is_empty() => {
+                info!("Loaded {} upstream CA certificates from {}", certs.len(), ca_file);
+                UPSTREAM_CA.insert(ca_file.clone(), Arc::new(certs.into_boxed_slice()));
+            }
+            Ok(_) => error!("No certificates found in upstream CA file {}", ca_file),
+            Err(e) => error!("Unable to load upstream CA file {}: {}", ca_file, e),
+        }
+    }
+    if let (Some(cert_file), Some(key_file)) = (&tls.client_cert, &tls.client_key) {
+        match load_cert_key(cert_file, key_file) {
+            Ok(cert_key) => {
+                UPSTREAM_CERTS.insert((cert_file.clone(), key_file.clone()), Arc::new(cert_key));
+            }
+            Err(e) => error!("Unable to load upstream client certificate {} / {}: {}", cert_file, key_file, e),
+        }
+    }
+}
+
+fn load_cert_key(cert_file: &str, key_file: &str) -> Result<CertKey, String> {
+    let certs = X509::stack_from_pem(&fs::read(cert_file).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    if certs.is_empty() {
+        return Err("no certificates found".to_string());
+    }
+    let key = PKey::private_key_from_pem(&fs::read(key_file).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    Ok(CertKey::new(certs, key))
+}
+
+// Certificate checks and client certificate of a TLS peer, without upstream_tls certificates are not verified
+pub fn apply_upstream_tls(peer: &mut HttpPeer, tls: Option<&UpstreamTls>) {
+    match tls {
+        Some(tls) => {
+            peer.options.verify_cert = tls.verify;
+            peer.options.verify_hostname = tls.verify;
+            peer.options.ca = upstream_ca(tls);
+            peer.client_cert_key = upstream_client_cert(tls);
+        }
+        None => {
+            peer.options.verify_cert = false;
+            peer.options.verify_hostname = false;
+        }
+    }
+}
+
+pub fn upstream_ca(tls: &UpstreamTls) -> Option<Arc<Box<[X509]>>> {
+    tls.ca_file.as_ref().and_then(|f| UPSTREAM_CA.get(f).map(|ca| ca.clone()))
+}
+
+pub fn upstream_client_cert(tls: &UpstreamTls) -> Option<Arc<CertKey>> {
+    let key = (tls.client_cert.clone()?, tls.client_key.clone()?);
+    UPSTREAM_CERTS.get(&key).map(|ck| ck.clone())
+}
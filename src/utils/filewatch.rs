@@ -35,13 +35,11 @@ pub async fn start(fp: String, toreturn: Sender<Configuration>) {
     while let Some(event) = local_rx.recv().await {
         match event {
             Ok(e) => match e.kind {
-                EventKind::Modify(ModifyKind::Data(_)) | EventKind::Create(..) | EventKind::Remove(..) => {
-                    if start.elapsed() > Duration::from_secs(2) {
-                        start = Instant::now();
-                        let snd = load_configuration(file_path, "filepath").await.0;
-                        if let Some(snd) = snd {
-                            toreturn.send(snd).await.unwrap();
-                        }
+                EventKind::Modify(ModifyKind::Data(_)) | EventKind::Create(..) | EventKind::Remove(..) if start.elapsed() > Duration::from_secs(2) => {
+                    start = Instant::now();
+                    let snd = load_configuration(file_path, "filepath").await.0;
+                    if let Some(snd) = snd {
+                        toreturn.send(snd).await.unwrap();
                     }
                 }
                 _ => (),
@@ -1,27 +1,47 @@
-use crate::utils::lazylock::REVERSE_STORE;
-use crate::utils::structs::{InnerMap, UpstreamsDashMap, UpstreamsIdMap};
+use crate::tls::upstream::apply_upstream_tls;
+use crate::utils::lazylock::{HASH_RINGS, HC_CLIENTS, REVERSE_STORE};
+use crate::utils::structs::{HealthParams, InnerMap, RouteKey, UpstreamTls, UpstreamsDashMap, UpstreamsIdMap};
 use crate::utils::tools::*;
+use crate::web::proxyprotocol::ProxyV2Connect;
 use dashmap::DashMap;
-use log::{error, warn};
-use reqwest::{Client, Version};
+use log::{error, info, warn};
+use pingora::http::RequestHeader;
+use pingora_core::connectors::http::Connector;
+use pingora_core::listeners::ALPN;
+use pingora_core::protocols::http::client::HttpSession;
+use pingora_core::upstreams::peer::HttpPeer;
+use reqwest::{Certificate, Client, Identity, Version};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
 use std::time::Duration;
-use tokio::time::interval;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{interval, Instant};
 use tonic::transport::Endpoint;
 
-pub async fn hc2(upslist: Arc<UpstreamsDashMap>, fullist: Arc<UpstreamsDashMap>, idlist: Arc<UpstreamsIdMap>, params: (&str, u64)) {
+const DEFAULT_HC_TIMEOUT: u64 = 2;
+const DEFAULT_HC_CONCURRENCY: usize = 64;
+
+static HC_CONNECTOR: LazyLock<Connector> = LazyLock::new(|| Connector::new(None));
+
+// params: (method, interval, probe timeout, max concurrent probes)
+pub async fn hc2(upslist: Arc<UpstreamsDashMap>, fullist: Arc<UpstreamsDashMap>, idlist: Arc<UpstreamsIdMap>, params: (&str, u64, Option<u64>, Option<usize>)) {
     let mut period = interval(Duration::from_secs(params.1));
-    let client = Client::builder().timeout(Duration::from_secs(params.1)).danger_accept_invalid_certs(true).build().unwrap();
+    let timeout = Duration::from_secs(params.2.unwrap_or(DEFAULT_HC_TIMEOUT));
+    let concurrency = params.3.unwrap_or(DEFAULT_HC_CONCURRENCY);
+    let client = Client::builder().timeout(timeout).danger_accept_invalid_certs(true).build().unwrap();
     loop {
         tokio::select! {
             _ = period.tick() => {
                 // populate_upstreams(&upslist, &fullist, &idlist, params, &client).await;
-                let totest = build_upstreams(&fullist, params.0, &client).await;
+                let totest = build_upstreams(&fullist, params.0, &client, timeout, concurrency).await;
                 if !compare_dashmaps(&totest, &upslist) {
                     clone_dashmap_into(&totest, &upslist);
                     clone_idmap_into(&totest, &idlist);
                     REVERSE_STORE.clear();
+                    HASH_RINGS.clear();
                 }
             }
         }
@@ -39,91 +59,311 @@ pub async fn populate_upstreams(upslist: &Arc<UpstreamsDashMap>, fullist: &Arc<U
 */
 
 pub async fn initiate_upstreams(fullist: UpstreamsDashMap) -> UpstreamsDashMap {
-    let client = Client::builder().timeout(Duration::from_secs(2)).danger_accept_invalid_certs(true).build().unwrap();
-    build_upstreams(&fullist, "HEAD", &client).await
+    let timeout = Duration::from_secs(DEFAULT_HC_TIMEOUT);
+    let client = Client::builder().timeout(timeout).danger_accept_invalid_certs(true).build().unwrap();
+    build_upstreams(&fullist, "HEAD", &client, timeout, DEFAULT_HC_CONCURRENCY).await
 }
 
-async fn build_upstreams(fullist: &UpstreamsDashMap, method: &str, client: &Client) -> UpstreamsDashMap {
+async fn build_upstreams(fullist: &UpstreamsDashMap, method: &str, client: &Client, timeout: Duration, concurrency: usize) -> UpstreamsDashMap {
     let totest: UpstreamsDashMap = DashMap::new();
     let fclone = clone_dashmap(fullist);
+    let limit = Arc::new(Semaphore::new(concurrency.max(1)));
+    let method: Arc<str> = Arc::from(method);
+    let mut probes = JoinSet::new();
+
     for val in fclone.iter() {
         let host = val.key();
         let inner = DashMap::new();
 
         for path_entry in val.value().iter() {
             let path = path_entry.key();
-            let mut innervec = Vec::new();
-
-            for upstream in path_entry.value().0.iter() {
-                let tls = if upstream.healthcheck.unwrap_or(true) {
-                    detect_tls(upstream.address.as_ref(), &upstream.port, client).await
-                } else {
-                    (false, None)
-                };
-
-                let is_h2 = matches!(tls.1, Some(Version::HTTP_2));
-
-                let mut scheme = InnerMap {
-                    address: upstream.address.clone(),
-                    port: upstream.port,
-                    is_ssl: tls.0,
-                    is_http2: is_h2,
-                    to_https: upstream.to_https,
-                    rate_limit: upstream.rate_limit,
-                    x4xx_limit: upstream.x4xx_limit,
-                    healthcheck: upstream.healthcheck,
-                    redirect_to: upstream.redirect_to.clone(),
-                    authorization: upstream.authorization.clone(),
-                };
-
-                if scheme.healthcheck.unwrap_or(true) {
-                    let link = if tls.0 {
-                        format!("https://{}:{}{}", upstream.address, upstream.port, path)
-                    } else {
-                        format!("http://{}:{}{}", upstream.address, upstream.port, path)
-                    };
-
-                    let resp = http_request(&link, method, "", client).await;
-                    if resp.0 {
-                        if resp.1 {
-                            scheme.is_http2 = is_h2; // could be adjusted further
-                        }
-                        innervec.push(Arc::from(scheme));
-                    } else {
-                        warn!("Dead Upstream : {}", link);
-                    }
-                } else {
-                    innervec.push(Arc::from(scheme));
-                }
+            for (idx, upstream) in path_entry.value().0.iter().enumerate() {
+                let (limit, client, method) = (limit.clone(), client.clone(), method.clone());
+                let (host, path, upstream) = (host.clone(), path.clone(), upstream.clone());
+                probes.spawn(async move {
+                    let _permit = limit.acquire_owned().await.ok();
+                    let alive = probe_upstream(&upstream, &host, &path, &method, &client, timeout).await;
+                    (host, path, idx, alive)
+                });
             }
-            inner.insert(path.clone(), (innervec, AtomicUsize::new(0)));
+            inner.insert(path.clone(), (Vec::new(), AtomicUsize::new(0)));
         }
         totest.insert(host.clone(), inner);
     }
+
+    let mut alive = Vec::new();
+    while let Some(joined) = probes.join_next().await {
+        match joined {
+            Ok((host, path, idx, Some(upstream))) => alive.push((host, path, idx, upstream)),
+            Ok(_) => {}
+            Err(e) => error!("Health check task failed: {}", e),
+        }
+    }
+    // Keep the configured order of servers, round robin and hash rings depend on it
+    alive.sort_by_key(|(_, _, idx, _)| *idx);
+    for (host, path, _, upstream) in alive {
+        if let Some(inner) = totest.get(&host) {
+            if let Some(mut entry) = inner.get_mut(&path) {
+                entry.0.push(upstream);
+            }
+        }
+    }
     totest
 }
 
-async fn http_request(url: &str, method: &str, payload: &str, client: &Client) -> (bool, bool) {
-    if !["POST", "GET", "HEAD"].contains(&method) {
-        error!("Method {} not supported. Only GET|POST|HEAD are supported ", method);
-        return (false, false);
-    }
-    async fn send_request(client: &Client, method: &str, url: &str, payload: &str) -> Option<reqwest::Response> {
-        match method {
-            "POST" => client.post(url).body(payload.to_owned()).send().await.ok(),
-            "GET" => client.get(url).send().await.ok(),
-            "HEAD" => client.head(url).send().await.ok(),
-            _ => None,
+// Probes a single upstream, returns it back with detected protocol if it is alive.
+async fn probe_upstream(upstream: &InnerMap, host: &str, path: &RouteKey, method: &str, client: &Client, timeout: Duration) -> Option<Arc<InnerMap>> {
+    let explicit = upstream.protocol.flags();
+    if !upstream.healthcheck.unwrap_or(true) {
+        let (is_ssl, is_http2) = explicit.unwrap_or_default();
+        return Some(Arc::new(InnerMap {
+            is_ssl,
+            is_http2,
+            ..upstream.clone()
+        }));
+    }
+
+    let params = upstream.hc_params.as_deref();
+    let deadline = params.and_then(|p| p.timeout).unwrap_or(timeout);
+    let probe_path = params.and_then(|p| p.path.as_deref()).unwrap_or_else(|| probe_path_of(path));
+    let probe_method = params.and_then(|p| p.method.as_deref()).unwrap_or(method);
+    if upstream.proxy_protocol {
+        let sni = upstream.sni.as_deref().unwrap_or(host);
+        let sni = if sni.starts_with(['*', '~']) { upstream.address.as_ref() } else { sni };
+        let known = explicit.or_else(|| upstream.state.tls_detected());
+        let (is_ssl, is_http2, ok) = tokio::time::timeout(deadline, probe_proxy_protocol(upstream, sni, known, probe_path, probe_method, params))
+            .await
+            .unwrap_or((false, false, false));
+        let scheme = if is_ssl { "https" } else { "http" };
+        let link = format!("{}://{}:{}{} (proxy protocol)", scheme, upstream.address, upstream.port, probe_path);
+        return probe_result(upstream, ok, is_ssl, is_http2, &link);
+    }
+    let started = Instant::now();
+    let (client, address) = match upstream.tls.as_deref() {
+        Some(tls) => tls_client(tls, upstream, upstream.sni.as_deref().unwrap_or(host), deadline).unwrap_or_else(|| (client.clone(), upstream.address.clone())),
+        None => (client.clone(), upstream.address.clone()),
+    };
+    let client = &client;
+    let (is_ssl, is_http2) = match explicit.or_else(|| upstream.state.tls_detected()) {
+        Some(cached) => cached,
+        None => match tokio::time::timeout(deadline, detect_tls(address.as_ref(), &upstream.port, client)).await {
+            Ok((ssl, Some(version))) => {
+                let is_h2 = version == Version::HTTP_2;
+                upstream.state.cache_tls(ssl, is_h2);
+                (ssl, is_h2)
+            }
+            Ok((ssl, None)) => (ssl, false),
+            Err(_) => (false, false),
+        },
+    };
+
+    let link = if is_ssl {
+        format!("https://{}:{}{}", address, upstream.port, probe_path)
+    } else {
+        format!("http://{}:{}{}", upstream.address, upstream.port, probe_path)
+    };
+
+    let remaining = deadline.saturating_sub(started.elapsed());
+    let resp = tokio::time::timeout(remaining, http_request(&link, probe_method, params, client))
+        .await
+        .unwrap_or((false, false));
+    probe_result(upstream, resp.0, is_ssl, is_http2, &link)
+}
+
+// Counts the probe towards rise/fall, returns the upstream with its detected protocol while it is healthy
+fn probe_result(upstream: &InnerMap, ok: bool, is_ssl: bool, is_http2: bool, link: &str) -> Option<Arc<InnerMap>> {
+    let params = upstream.hc_params.as_deref();
+    let (rise, fall) = params.map_or((1, 1), |p| (p.rise, p.fall));
+    let (healthy, changed) = upstream.state.record_probe(ok, rise, fall);
+    if changed {
+        if healthy {
+            info!("Upstream is back alive after {} successful checks : {}", rise, link);
+        } else {
+            warn!("Dead Upstream after {} failed checks : {}", fall, link);
         }
     }
+    healthy.then(|| {
+        Arc::new(InnerMap {
+            is_ssl,
+            is_http2,
+            ..upstream.clone()
+        })
+    })
+}
 
-    match send_request(client, method, url, payload).await {
-        Some(response) => {
-            let status = response.status().as_u16();
-            ((99..499).contains(&status), false)
+// Upstreams with proxy_protocol drop connections without a PROXY header, so they are probed through the pingora
+// connector with a LOCAL v2 header, like balancers probe their backends. Returns (is_ssl, is_http2, healthy).
+async fn probe_proxy_protocol(upstream: &InnerMap, sni: &str, known: Option<(bool, bool)>, path: &str, method: &str, params: Option<&HealthParams>) -> (bool, bool, bool) {
+    if let Some((is_ssl, is_http2)) = known {
+        let alpn = if is_http2 { ALPN::H2 } else { ALPN::H1 };
+        let healthy = proxy_protocol_request(upstream, sni, is_ssl, alpn, path, method, params)
+            .await
+            .is_ok_and(|(healthy, _)| healthy);
+        return (is_ssl, is_http2, healthy);
+    }
+    // TLS is tried first, an answer on either protocol settles it for the next probes
+    for (is_ssl, alpn) in [(true, ALPN::H2H1), (false, ALPN::H1)] {
+        if let Ok((healthy, is_http2)) = proxy_protocol_request(upstream, sni, is_ssl, alpn, path, method, params).await {
+            upstream.state.cache_tls(is_ssl, is_http2);
+            return (is_ssl, is_http2, healthy);
         }
-        None => (ping_grpc(url).await, true),
     }
+    (false, false, false)
+}
+
+// Returns whether the response is healthy and whether HTTP/2 was negotiated
+async fn proxy_protocol_request(
+    upstream: &InnerMap,
+    sni: &str,
+    is_ssl: bool,
+    alpn: ALPN,
+    path: &str,
+    method: &str,
+    params: Option<&HealthParams>,
+) -> pingora::Result<(bool, bool)> {
+    let mut peer = HttpPeer::new((&*upstream.address, upstream.port), is_ssl, sni.to_string());
+    peer.options.alpn = alpn;
+    if is_ssl {
+        apply_upstream_tls(&mut peer, upstream.tls.as_deref());
+    }
+    peer.options.custom_l4 = Some(Arc::new(ProxyV2Connect { addresses: None }));
+    let (mut session, _) = HC_CONNECTOR.get_http_session(&peer).await?;
+    let is_http2 = matches!(session, HttpSession::H2(_));
+
+    let mut request = RequestHeader::build(method, path.as_bytes(), None)?;
+    request.insert_header("Host", sni)?;
+    for (k, v) in params.map(|p| p.headers.as_slice()).unwrap_or_default() {
+        request.insert_header(k.to_string(), v.as_ref())?;
+    }
+    session.write_request_header(Box::new(request)).await?;
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    let status = session.response_header().map_or(0, |r| r.status.as_u16());
+    if !status_ok(status, params) {
+        return Ok((false, is_http2));
+    }
+    if params.is_none_or(|p| p.body_contains.is_none() && p.body_regex.is_none()) {
+        return Ok((true, is_http2));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = session.read_response_body().await? {
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body_ok(&String::from_utf8_lossy(&body), params), is_http2))
+}
+
+// Exact routes "= /path" are probed on their path, regex routes on "/", rules and groups on the path of their route
+fn probe_path_of(key: &RouteKey) -> &str {
+    match key.path.strip_prefix('=') {
+        Some(exact) => exact.trim(),
+        None if key.path.starts_with('/') => &key.path,
+        None => "/",
+    }
+}
+
+// Health client honoring upstream_tls. With verification on, the upstream IP is resolved from the SNI name,
+// so certificates are checked against the same name as proxied traffic. Returns the client and the host to probe.
+fn tls_client(tls: &UpstreamTls, upstream: &InnerMap, sni: &str, timeout: Duration) -> Option<(Client, Arc<str>)> {
+    let resolve = match upstream.address.parse::<IpAddr>() {
+        Ok(ip) if tls.verify && !sni.starts_with(['*', '~']) => Some(SocketAddr::new(ip, upstream.port)),
+        _ => None,
+    };
+    let address: Arc<str> = if resolve.is_some() { Arc::from(sni) } else { upstream.address.clone() };
+    let key = (tls.clone(), address.clone(), resolve, timeout);
+    if let Some(client) = HC_CLIENTS.get(&key) {
+        return Some((client.clone(), address));
+    }
+
+    let mut builder = Client::builder().timeout(timeout).danger_accept_invalid_certs(!tls.verify);
+    if let Some(addr) = resolve {
+        builder = builder.resolve(sni, addr);
+    }
+    if let Some(ca_file) = &tls.ca_file {
+        match fs::read(ca_file.as_ref())
+            .map_err(|e| e.to_string())
+            .and_then(|pem| Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string()))
+        {
+            Ok(certs) => {
+                for cert in certs {
+                    builder = builder.add_root_certificate(cert);
+                }
+            }
+            Err(e) => error!("Unable to load upstream CA file {} for health checks: {}", ca_file, e),
+        }
+    }
+    if let (Some(cert_file), Some(key_file)) = (&tls.client_cert, &tls.client_key) {
+        match (fs::read(cert_file.as_ref()), fs::read(key_file.as_ref())) {
+            (Ok(mut pem), Ok(key)) => {
+                pem.push(b'\n');
+                pem.extend_from_slice(&key);
+                match Identity::from_pem(&pem) {
+                    Ok(identity) => builder = builder.identity(identity),
+                    Err(e) => error!("Invalid upstream client certificate {} for health checks: {}", cert_file, e),
+                }
+            }
+            _ => error!("Unable to read upstream client certificate {} / {}", cert_file, key_file),
+        }
+    }
+    match builder.build() {
+        Ok(client) => {
+            HC_CLIENTS.insert(key, client.clone());
+            Some((client, address))
+        }
+        Err(e) => {
+            error!("Unable to build health check client for {}:{}: {}", upstream.address, upstream.port, e);
+            None
+        }
+    }
+}
+
+async fn http_request(url: &str, method: &str, params: Option<&HealthParams>, client: &Client) -> (bool, bool) {
+    let mut request = match method {
+        "POST" => client.post(url),
+        "GET" => client.get(url),
+        "HEAD" => client.head(url),
+        _ => {
+            error!("Method {} not supported. Only GET|POST|HEAD are supported ", method);
+            return (false, false);
+        }
+    };
+    if let Some(p) = params {
+        for (k, v) in p.headers.iter() {
+            request = request.header(k.as_ref(), v.as_ref());
+        }
+        if let Some(timeout) = p.timeout {
+            request = request.timeout(timeout);
+        }
+    }
+
+    match request.send().await {
+        Ok(response) => {
+            if !status_ok(response.status().as_u16(), params) {
+                return (false, false);
+            }
+            if params.is_none_or(|p| p.body_contains.is_none() && p.body_regex.is_none()) {
+                return (true, false);
+            }
+            let body = response.text().await.unwrap_or_default();
+            (body_ok(&body, params), false)
+        }
+        Err(_) => (ping_grpc(url).await, true),
+    }
+}
+
+fn status_ok(status: u16, params: Option<&HealthParams>) -> bool {
+    match params.filter(|p| !p.expected_status.is_empty()) {
+        Some(p) => p.expected_status.iter().any(|(from, to)| (*from..=*to).contains(&status)),
+        None => (99..499).contains(&status),
+    }
+}
+
+fn body_ok(body: &str, params: Option<&HealthParams>) -> bool {
+    let Some(p) = params else {
+        return true;
+    };
+    let contains = p.body_contains.as_ref().is_none_or(|needle| body.contains(needle.as_ref()));
+    let matches = p.body_regex.as_ref().is_none_or(|re| re.0.is_match(body));
+    contains && matches
 }
 
 pub async fn ping_grpc(addr: &str) -> bool {
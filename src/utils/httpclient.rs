@@ -1,5 +1,5 @@
 use crate::utils::kuberconsul::{match_path, ConsulService, KubeEndpoints};
-use crate::utils::structs::{GlobalServiceMapping, InnerMap};
+use crate::utils::structs::{GlobalServiceMapping, HashOn, InnerMap, LbMethod, RouteKey, UpstreamProtocol};
 use axum::http::{HeaderMap, HeaderValue};
 use dashmap::DashMap;
 use reqwest::Client;
@@ -7,7 +7,7 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::time::Duration;
 
-pub async fn for_consul(url: String, token: Option<String>, conf: &GlobalServiceMapping) -> Option<DashMap<Arc<str>, (Vec<Arc<InnerMap>>, AtomicUsize)>> {
+pub async fn for_consul(url: String, token: Option<String>, conf: &GlobalServiceMapping) -> Option<DashMap<RouteKey, (Vec<Arc<InnerMap>>, AtomicUsize)>> {
     let client = Client::builder().timeout(Duration::from_secs(2)).danger_accept_invalid_certs(true).build().ok()?;
     let mut headers = HeaderMap::new();
     if let Some(token) = token {
@@ -20,22 +20,26 @@ pub async fn for_consul(url: String, token: Option<String>, conf: &GlobalService
         return None;
     }
     let mut inner_vec = Vec::new();
-    let upstreams: DashMap<Arc<str>, (Vec<Arc<InnerMap>>, AtomicUsize)> = DashMap::new();
+    let upstreams: DashMap<RouteKey, (Vec<Arc<InnerMap>>, AtomicUsize)> = DashMap::new();
     let endpoints: Vec<ConsulService> = resp.json().await.ok()?;
+    let (protocol, sni) = explicit_protocol(conf);
+    let (is_ssl, is_http2) = protocol.flags().unwrap_or_default();
     for subsets in endpoints {
         let addr = subsets.tagged_addresses.get("lan_ipv4").unwrap().address.clone();
         let prt = subsets.tagged_addresses.get("lan_ipv4").unwrap().port;
         let to_add = Arc::from(InnerMap {
             address: Arc::from(&*addr),
             port: prt,
-            is_ssl: false,
-            is_http2: false,
             to_https: conf.to_https.unwrap_or(false),
             rate_limit: conf.rate_limit,
             x4xx_limit: conf.x4xx_limit,
-            redirect_to: None,
-            healthcheck: None,
-            authorization: None,
+            lb_method: conf.lb_method.as_deref().map(LbMethod::from_str).unwrap_or_default(),
+            hash_on: conf.hash_on.as_deref().map(HashOn::from_str).unwrap_or_default(),
+            is_ssl,
+            is_http2,
+            protocol,
+            sni: sni.clone(),
+            ..InnerMap::new()
         });
         inner_vec.push(to_add);
     }
@@ -43,7 +47,7 @@ pub async fn for_consul(url: String, token: Option<String>, conf: &GlobalService
     Some(upstreams)
 }
 
-pub async fn for_kuber(url: &str, token: &str, conf: &GlobalServiceMapping) -> Option<DashMap<Arc<str>, (Vec<Arc<InnerMap>>, AtomicUsize)>> {
+pub async fn for_kuber(url: &str, token: &str, conf: &GlobalServiceMapping) -> Option<DashMap<RouteKey, (Vec<Arc<InnerMap>>, AtomicUsize)>> {
     let to = Duration::from_secs(10);
     let client = Client::builder().timeout(Duration::from_secs(10)).danger_accept_invalid_certs(true).build().ok()?;
     let resp = client.get(url).timeout(to).bearer_auth(token).send().await.ok()?;
@@ -52,8 +56,10 @@ pub async fn for_kuber(url: &str, token: &str, conf: &GlobalServiceMapping) -> O
         return None;
     }
     let endpoints: KubeEndpoints = resp.json().await.ok()?;
+    let (protocol, sni) = explicit_protocol(conf);
+    let (is_ssl, is_http2) = protocol.flags().unwrap_or_default();
 
-    let upstreams: DashMap<Arc<str>, (Vec<Arc<InnerMap>>, AtomicUsize)> = DashMap::new();
+    let upstreams: DashMap<RouteKey, (Vec<Arc<InnerMap>>, AtomicUsize)> = DashMap::new();
 
     if let Some(subsets) = endpoints.subsets {
         for subset in subsets {
@@ -65,14 +71,16 @@ pub async fn for_kuber(url: &str, token: &str, conf: &GlobalServiceMapping) -> O
                         let to_add = Arc::from(InnerMap {
                             address: Arc::from(addr.ip.clone()),
                             port: port.port,
-                            is_ssl: false,
-                            is_http2: false,
                             to_https: conf.to_https.unwrap_or(false),
                             rate_limit: conf.rate_limit,
                             x4xx_limit: conf.x4xx_limit,
-                            healthcheck: None,
-                            redirect_to: None,
-                            authorization: None,
+                            lb_method: conf.lb_method.as_deref().map(LbMethod::from_str).unwrap_or_default(),
+                            hash_on: conf.hash_on.as_deref().map(HashOn::from_str).unwrap_or_default(),
+                            is_ssl,
+                            is_http2,
+                            protocol,
+                            sni: sni.clone(),
+                            ..InnerMap::new()
                         });
                         inner_vec.push(to_add);
                     }
@@ -83,3 +91,8 @@ pub async fn for_kuber(url: &str, token: &str, conf: &GlobalServiceMapping) -> O
     }
     Some(upstreams)
 }
+
+fn explicit_protocol(conf: &GlobalServiceMapping) -> (UpstreamProtocol, Option<Arc<str>>) {
+    let protocol = conf.upstream_protocol.as_deref().map(UpstreamProtocol::from_str).unwrap_or_default();
+    (protocol, conf.upstream_sni.as_deref().map(Arc::from))
+}
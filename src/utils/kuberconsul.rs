@@ -1,6 +1,6 @@
 use crate::utils::httpclient;
 use crate::utils::parceyaml::build_headers;
-use crate::utils::structs::{Configuration, GlobalServiceMapping, InnerMap, UpstreamsDashMap};
+use crate::utils::structs::{Configuration, GlobalServiceMapping, HeaderOp, HeaderRule, InnerMap, RouteKey, UpstreamsDashMap};
 use crate::utils::tools::{clone_dashmap_into, compare_dashmaps, print_upstreams};
 use async_trait::async_trait;
 use dashmap::DashMap;
@@ -52,7 +52,7 @@ pub struct ConsulTaggedAddress {
     pub port: u16,
 }
 #[allow(clippy::type_complexity)]
-pub fn list_to_upstreams(lt: Option<DashMap<Arc<str>, (Vec<Arc<InnerMap>>, AtomicUsize)>>, upstreams: &UpstreamsDashMap, i: &GlobalServiceMapping) {
+pub fn list_to_upstreams(lt: Option<DashMap<RouteKey, (Vec<Arc<InnerMap>>, AtomicUsize)>>, upstreams: &UpstreamsDashMap, i: &GlobalServiceMapping) {
     if let Some(list) = lt {
         match upstreams.get(&*i.hostname.clone()) {
             Some(upstr) => {
@@ -67,13 +67,13 @@ pub fn list_to_upstreams(lt: Option<DashMap<Arc<str>, (Vec<Arc<InnerMap>>, Atomi
     }
 }
 
-pub fn match_path(conf: &GlobalServiceMapping, upstreams: &DashMap<Arc<str>, (Vec<Arc<InnerMap>>, AtomicUsize)>, values: Vec<Arc<InnerMap>>) {
+pub fn match_path(conf: &GlobalServiceMapping, upstreams: &DashMap<RouteKey, (Vec<Arc<InnerMap>>, AtomicUsize)>, values: Vec<Arc<InnerMap>>) {
     match conf.path {
         Some(ref p) => {
-            upstreams.insert(Arc::from(p.clone()), (values, AtomicUsize::new(0)));
+            upstreams.insert(RouteKey::new(p), (values, AtomicUsize::new(0)));
         }
         None => {
-            upstreams.insert(Arc::from("/"), (values, AtomicUsize::new(0)));
+            upstreams.insert(RouteKey::new("/"), (values, AtomicUsize::new(0)));
         }
     }
 }
@@ -115,16 +115,16 @@ impl ServiceDiscovery for KubernetesDiscovery {
                 if let Some(kuber) = config.kubernetes.clone() {
                     if let Some(svc) = kuber.services {
                         for service in svc {
-                            let header_list: DashMap<Arc<str>, Vec<(String, Arc<str>)>> = DashMap::new();
+                            let header_list: DashMap<RouteKey, Vec<HeaderRule>> = DashMap::new();
                             let mut hl = Vec::new();
-                            build_headers(&service.client_headers, config.as_ref(), &mut hl);
+                            build_headers(&service.client_headers, config.as_ref(), HeaderOp::Append, &mut hl);
                             if !hl.is_empty() {
                                 match service.path.clone() {
                                     Some(path) => {
-                                        header_list.insert(Arc::from(path.as_str()), hl);
+                                        header_list.insert(RouteKey::new(&path), hl);
                                     }
                                     None => {
-                                        header_list.insert(Arc::from("/"), hl);
+                                        header_list.insert(RouteKey::new("/"), hl);
                                     }
                                 }
 
@@ -181,14 +181,14 @@ impl ServiceDiscovery for ConsulDiscovery {
                     for i in svc {
                         let header_list = DashMap::new();
                         let mut hl = Vec::new();
-                        build_headers(&i.client_headers, config.as_ref(), &mut hl);
+                        build_headers(&i.client_headers, config.as_ref(), HeaderOp::Append, &mut hl);
                         if !hl.is_empty() {
                             match i.path.clone() {
                                 Some(path) => {
-                                    header_list.insert(Arc::from(path.as_str()), hl);
+                                    header_list.insert(RouteKey::new(&path), hl);
                                 }
                                 None => {
-                                    header_list.insert(Arc::from("/"), hl);
+                                    header_list.insert(RouteKey::new("/"), hl);
                                 }
                             }
                             // header_list.insert(i.path.clone().unwrap_or("/".to_string()), hl);
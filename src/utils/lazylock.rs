@@ -1,11 +1,24 @@
+use crate::utils::structs::UpstreamTls;
+use crate::web::gethosts::{HashRing, RouteTable};
 use dashmap::DashMap;
 use moka::sync::Cache;
+use pingora::tls::x509::X509;
+use pingora_core::utils::tls::CertKey;
 use pingora_limits::rate::Rate;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, LazyLock};
 use std::time::Duration;
 
+type CaChain = Arc<Box<[X509]>>;
+type CertKeyFiles = (Arc<str>, Arc<str>);
+type HcClientKey = (UpstreamTls, Arc<str>, Option<SocketAddr>, Duration);
+
 pub static REVERSE_STORE: LazyLock<DashMap<String, String>> = LazyLock::new(DashMap::new);
 pub static RATE_LIMITER: LazyLock<Rate> = LazyLock::new(|| Rate::new(Duration::from_secs(1)));
 pub static REQUESTS_4XX: LazyLock<Cache<IpAddr, u32>> = LazyLock::new(|| Cache::builder().time_to_live(Duration::from_secs(1)).build());
 pub static LOCALHOST: LazyLock<Arc<str>> = LazyLock::new(|| Arc::from("localhost"));
+pub static HASH_RINGS: LazyLock<DashMap<u64, Arc<HashRing>>> = LazyLock::new(DashMap::new);
+pub static ROUTE_TABLES: LazyLock<DashMap<Arc<str>, Arc<RouteTable>>> = LazyLock::new(DashMap::new);
+pub static UPSTREAM_CA: LazyLock<DashMap<Arc<str>, CaChain>> = LazyLock::new(DashMap::new);
+pub static UPSTREAM_CERTS: LazyLock<DashMap<CertKeyFiles, Arc<CertKey>>> = LazyLock::new(DashMap::new);
+pub static HC_CLIENTS: LazyLock<DashMap<HcClientKey, reqwest::Client>> = LazyLock::new(DashMap::new);
@@ -38,6 +38,44 @@ pub static REQUESTS_BY_METHOD: LazyLock<IntCounterVec> =
 pub static REQUESTS_BY_UPSTREAM: LazyLock<IntCounterVec> =
     LazyLock::new(|| register_int_counter_vec!("aralez_requests_by_upstream", "Number of requests by UPSTREAM server", &["upstream"]).unwrap());
 
+pub static UPSTREAM_EJECTIONS: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("aralez_upstream_ejections_total", "Number of upstreams ejected by outlier detection", &["reason"]).unwrap());
+
+pub static UPSTREAM_RETRIES: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("aralez_upstream_retries_total", "Number of requests retried on another upstream", &["reason"]).unwrap());
+
+pub static UPSTREAM_TIMEOUTS: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("aralez_upstream_timeouts_total", "Number of requests failed with 504 by timeout kind", &["kind"]).unwrap());
+
+pub static MIRROR_REQUESTS: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("aralez_mirror_requests_total", "Mirrored requests by result", &["result"]).unwrap());
+
+pub static COMPRESSED_RESPONSES: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("aralez_compressed_responses_total", "Responses compressed by the proxy by algorithm", &["algorithm"]).unwrap());
+
+pub static COMPRESSION_RATIO: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "aralez_compression_ratio",
+        "Compressed to original size of compressed responses",
+        vec![0.05, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0]
+    )
+    .unwrap()
+});
+
+pub static COMPRESSION_TIME: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "aralez_compression_seconds",
+        "CPU time spent compressing a response in seconds",
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5]
+    )
+    .unwrap()
+});
+
+pub static CACHE_REQUESTS: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("aralez_cache_requests_total", "Requests to cached paths by cache status", &["status"]).unwrap());
+
+pub static CACHE_SIZE: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!("aralez_cache_bytes", "Size of cached responses in bytes").unwrap());
+
 pub static REQUESTS_BY_VERSION: LazyLock<IntCounterVec> =
     LazyLock::new(|| register_int_counter_vec!("aralez_requests_by_version_total", "Number of requests by HTTP versions", &["version"]).unwrap());
 
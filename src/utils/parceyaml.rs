@@ -1,8 +1,10 @@
+use crate::tls::upstream::load_upstream_tls;
 use crate::utils::healthcheck;
-use crate::utils::lazylock::REVERSE_STORE;
+use crate::utils::lazylock::{HASH_RINGS, HC_CLIENTS, REVERSE_STORE, ROUTE_TABLES};
 use crate::utils::state::{is_first_run, mark_not_first_run};
 use crate::utils::structs::*;
 use crate::utils::tools::{clone_dashmap, clone_dashmap_into, print_upstreams};
+use crate::web::gethosts::{Route, RouteRule, RouteTable, RouteTarget, TrafficSplit, ValueMatch};
 use dashmap::DashMap;
 use log::LevelFilter;
 use log::{error, info, warn};
@@ -11,10 +13,13 @@ use log4rs::{
     config::{Appender, Config as Log4rsConfig, Root},
     encode::pattern::PatternEncoder,
 };
+use pingora::http::Method;
+use regex::{Regex, RegexSet};
 use std::collections::HashMap;
 use std::path::Path;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 use std::{env, fs};
 
 pub static DOMAINS: LazyLock<DashMap<String, bool>> = LazyLock::new(DashMap::new);
@@ -154,29 +159,16 @@ pub async fn load_configuration(d: &str, kind: &str) -> (Option<Configuration>,
 }
 
 async fn populate_headers_and_auth(config: &mut Configuration, parsed: &Config) {
-    let mut ch: Vec<(String, Arc<str>)> = Vec::new();
-    if let Some(headers) = &parsed.client_headers {
-        for header in headers {
-            if let Some((key, val)) = header.split_once(':') {
-                ch.push((key.to_string(), Arc::from(val)));
-            }
-        }
-    }
-
-    let global_headers: DashMap<Arc<str>, Vec<(String, Arc<str>)>> = DashMap::new();
-    global_headers.insert(Arc::from("/"), ch);
+    let mut ch: Vec<HeaderRule> = Vec::new();
+    build_headers(&parsed.client_headers, config, HeaderOp::Append, &mut ch);
+    let global_headers: DashMap<RouteKey, Vec<HeaderRule>> = DashMap::new();
+    global_headers.insert(RouteKey::new("/"), ch);
     config.client_headers.insert(Arc::from("GLOBAL_CLIENT_HEADERS"), global_headers);
 
-    let mut sh: Vec<(String, Arc<str>)> = Vec::new();
-    if let Some(headers) = &parsed.server_headers {
-        for header in headers {
-            if let Some((key, val)) = header.split_once(':') {
-                sh.push((key.to_string(), Arc::from(val.trim())));
-            }
-        }
-    }
-    let server_global_headers: DashMap<Arc<str>, Vec<(String, Arc<str>)>> = DashMap::new();
-    server_global_headers.insert(Arc::from("/"), sh);
+    let mut sh: Vec<HeaderRule> = Vec::new();
+    build_headers(&parsed.server_headers, config, HeaderOp::Set, &mut sh);
+    let server_global_headers: DashMap<RouteKey, Vec<HeaderRule>> = DashMap::new();
+    server_global_headers.insert(RouteKey::new("/"), sh);
     config.server_headers.insert(Arc::from("GLOBAL_SERVER_HEADERS"), server_global_headers);
     config.extraparams.to_https = parsed.to_https;
     config.extraparams.sticky_sessions = parsed.sticky_sessions;
@@ -198,58 +190,186 @@ async fn populate_headers_and_auth(config: &mut Configuration, parsed: &Config)
 
 async fn populate_file_upstreams(config: &mut Configuration, parsed: &Config) {
     let imtdashmap = UpstreamsDashMap::new();
+    let mut route_tables = Vec::new();
     if let Some(upstreams) = &parsed.upstreams {
         for (hostname, host_config) in upstreams {
             let path_map = DashMap::new();
             let client_header_list = DashMap::new();
             let server_header_list = DashMap::new();
-            for (path, path_config) in &host_config.paths {
-                if let Some(rate) = &path_config.rate_limit {
-                    info!("Applied Rate Limit for {} : {} request per second", hostname, rate);
+            let host_error_pages = host_config.error_pages.as_ref().map(build_error_pages);
+            let mut routes = RouteTable {
+                host_settings: host_error_pages.as_ref().map(|pages| {
+                    Arc::new(PathSettings {
+                        error_pages: Some(pages.clone()),
+                        ..PathSettings::default()
+                    })
+                }),
+                ..RouteTable::default()
+            };
+            for (path, path_rules) in &host_config.paths {
+                let priority = path_rules.entries().iter().find_map(|c| c.priority).unwrap_or(0);
+                // "= /path" exact match, "~ regex" regex match, everything else is a prefix match on path segments
+                if let Some(exact) = path.strip_prefix('=') {
+                    routes.exact.insert(Arc::from(exact.trim()), Arc::from(path.as_str()));
+                } else if let Some(pattern) = path.strip_prefix('~') {
+                    match Regex::new(pattern.trim()) {
+                        Ok(re) => routes.regex.push((priority, re, Arc::from(path.as_str()))),
+                        Err(e) => {
+                            error!("Invalid regex route {} for {}: {}", pattern, hostname, e);
+                            continue;
+                        }
+                    }
+                } else if !path.starts_with('/') {
+                    warn!("Path {} for {} does not start with '/', it will never match", path, hostname);
                 }
-                let mut hl: Vec<(String, Arc<str>)> = Vec::new();
-                let mut sl: Vec<(String, Arc<str>)> = Vec::new();
-                build_headers(&path_config.client_headers, config, &mut hl);
-                build_headers(&path_config.server_headers, config, &mut sl);
-                client_header_list.insert(Arc::from(path.as_str()), hl);
-                server_header_list.insert(Arc::from(path.as_str()), sl);
-                let mut server_list = Vec::new();
-                for server in &path_config.servers {
-                    let mut path_auth: Option<Arc<InnerAuth>> = None;
-                    if let Some(pa) = &path_config.authorization {
-                        let y: InnerAuth = InnerAuth {
-                            auth_type: Arc::from(pa.auth_type.clone()),
-                            auth_cred: Arc::from(pa.auth_cred.clone().unwrap_or_default()),
-                        };
-                        path_auth = Some(Arc::from(y));
+                let mut route = Route::default();
+                for (n, path_config) in path_rules.entries().iter().enumerate() {
+                    let rule = match &path_config.match_rule {
+                        Some(m) => match build_rule(m) {
+                            Some(rule) => Some(rule),
+                            None => {
+                                error!("Invalid match rule {} of {}{}, skipping", n + 1, hostname, path);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let key = match rule {
+                        Some(_) => RouteKey::new(path).with_rule(n + 1),
+                        None => RouteKey::new(path),
+                    };
+                    if let Some(rate) = &path_config.rate_limit {
+                        info!("Applied Rate Limit for {} : {} request per second", hostname, rate);
                     }
+                    let mut hl: Vec<HeaderRule> = Vec::new();
+                    let mut sl: Vec<HeaderRule> = Vec::new();
+                    build_headers(&path_config.client_headers, config, HeaderOp::Append, &mut hl);
+                    build_headers(&path_config.server_headers, config, HeaderOp::Set, &mut sl);
+                    client_header_list.insert(key.clone(), hl);
+                    server_header_list.insert(key.clone(), sl);
+                    let (healthcheck, hc_params) = build_healthcheck(&path_config.healthcheck);
+                    let outlier = path_config.outlier_detection.as_ref().map(|od| {
+                        Arc::new(OutlierParams {
+                            consecutive_5xx: od.consecutive_5xx.unwrap_or(5),
+                            consecutive_connect_failures: od.consecutive_connect_failures.unwrap_or(5),
+                            base_ejection_time: Duration::from_secs(od.base_ejection_time.unwrap_or(30)),
+                            max_ejection_time: Duration::from_secs(od.max_ejection_time.unwrap_or(300)),
+                            max_ejection_percent: od.max_ejection_percent.unwrap_or(50).min(100),
+                        })
+                    });
+                    let protocol = path_config.upstream_protocol.as_deref().map(UpstreamProtocol::from_str).unwrap_or_default();
+                    let upstream_tls = path_config.upstream_tls.as_ref().map(|t| {
+                        let tls = UpstreamTls {
+                            verify: t.verify.unwrap_or(true),
+                            ca_file: t.ca_file.as_deref().map(Arc::from),
+                            client_cert: t.client_cert.as_deref().map(Arc::from),
+                            client_key: t.client_key.as_deref().map(Arc::from),
+                        };
+                        if tls.client_cert.is_some() != tls.client_key.is_some() {
+                            warn!("Both client_cert and client_key are required for upstream mTLS of {}{}", hostname, path);
+                        }
+                        load_upstream_tls(&tls);
+                        Arc::new(tls)
+                    });
+                    let sni = path_config
+                        .upstream_tls
+                        .as_ref()
+                        .and_then(|t| t.sni.as_deref())
+                        .or(path_config.upstream_sni.as_deref())
+                        .map(Arc::from);
+                    let retry = build_retry(path_config);
+                    let timeouts = build_timeouts(path_config);
+                    let rewrite = build_rewrite(path_config);
+                    let host_header = path_config.host_header.as_deref().map(Arc::from);
+                    let mirror = path_config.mirror.as_ref().and_then(|m| build_mirror(m, upstream_tls.clone()));
+                    let compression = path_config.compression.as_ref().and_then(build_compression);
+                    let cache = path_config.cache.as_ref().map(build_cache);
+                    let error_pages = path_config.error_pages.as_ref().map(build_error_pages).or_else(|| host_error_pages.clone());
+                    let (targets, split): (Vec<(RouteKey, &Vec<String>)>, _) = match &path_config.groups {
+                        Some(groups) => {
+                            if !path_config.servers.is_empty() {
+                                warn!("Path {}{} has groups, its servers are ignored", hostname, path);
+                            }
+                            let targets: Vec<(RouteKey, &Vec<String>)> = groups.iter().map(|(name, group)| (key.with_group(name), &group.servers)).collect();
+                            let split = TrafficSplit {
+                                groups: groups.values().zip(&targets).map(|(group, (target, _))| (group.weight, target.clone())).collect(),
+                                pin_on: path_config.pin_on.as_deref().map(HashOn::from_str),
+                            };
+                            (targets, Some(split))
+                        }
+                        None => (vec![(key.clone(), &path_config.servers)], None),
+                    };
+                    for (target, servers) in targets {
+                        let mut server_list = Vec::new();
+                        for server in servers {
+                            let mut path_auth: Option<Arc<InnerAuth>> = None;
+                            if let Some(pa) = &path_config.authorization {
+                                let y: InnerAuth = InnerAuth {
+                                    auth_type: Arc::from(pa.auth_type.clone()),
+                                    auth_cred: Arc::from(pa.auth_cred.clone().unwrap_or_default()),
+                                };
+                                path_auth = Some(Arc::from(y));
+                            }
+
+                            let lb_method = path_config.lb_method.as_deref().map(LbMethod::from_str).unwrap_or_default();
+                            let hash_on = path_config.hash_on.as_deref().map(HashOn::from_str).unwrap_or_default();
 
-                    let redirect_link = path_config.redirect_to.as_ref().map(|www| Arc::from(www.as_str()));
-
-                    if let Some((ip, port_str)) = server.rsplit_once(':') {
-                        if let Ok(port) = port_str.parse::<u16>() {
-                            server_list.push(Arc::from(InnerMap {
-                                address: Arc::from(ip),
-                                port,
-                                is_ssl: false,
-                                is_http2: false,
-                                to_https: path_config.to_https.unwrap_or(false),
-                                rate_limit: path_config.rate_limit,
-                                x4xx_limit: path_config.x4xx_limit,
-                                healthcheck: path_config.healthcheck,
-                                redirect_to: redirect_link,
-                                authorization: path_auth,
-                            }));
+                            if let Some((ip, port, weight, scheme)) = parse_server(server) {
+                                let protocol = scheme.unwrap_or(protocol);
+                                let (is_ssl, is_http2) = protocol.flags().unwrap_or_default();
+                                server_list.push(Arc::from(InnerMap {
+                                    address: Arc::from(ip),
+                                    port,
+                                    weight,
+                                    is_ssl,
+                                    is_http2,
+                                    to_https: path_config.to_https.unwrap_or(false),
+                                    rate_limit: path_config.rate_limit,
+                                    x4xx_limit: path_config.x4xx_limit,
+                                    healthcheck,
+                                    hc_params: hc_params.clone(),
+                                    authorization: path_auth,
+                                    lb_method,
+                                    hash_on: hash_on.clone(),
+                                    outlier: outlier.clone(),
+                                    protocol,
+                                    sni: sni.clone(),
+                                    tls: upstream_tls.clone(),
+                                    retry: retry.clone(),
+                                    timeouts: timeouts.clone(),
+                                    rewrite: rewrite.clone(),
+                                    host_header: host_header.clone(),
+                                    mirror: mirror.clone(),
+                                    proxy_protocol: path_config.proxy_protocol.unwrap_or(false),
+                                    compression: compression.clone(),
+                                    cache: cache.clone(),
+                                    ..InnerMap::new()
+                                }));
+                            }
                         }
+                        path_map.insert(target, (server_list, AtomicUsize::new(0)));
+                    }
+                    let settings = Arc::new(PathSettings {
+                        error_pages,
+                        redirect_to: path_config.redirect_to.as_deref().map(Arc::from),
+                        redirect: build_redirect(path_config),
+                    });
+                    let target = RouteTarget { key, split, settings };
+                    match rule {
+                        Some(rule) => route.rules.push((rule, target)),
+                        None => route.fallback = Some(target),
                     }
                 }
-                path_map.insert(Arc::from(path.clone()), (server_list, AtomicUsize::new(0)));
+                routes.routes.insert(Arc::from(path.as_str()), route);
             }
+            routes.regex.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.as_str().cmp(b.1.as_str())));
+            route_tables.push((Arc::from(hostname.as_str()), Arc::new(routes)));
             config.client_headers.insert(Arc::from(hostname.clone()), client_header_list);
             config.server_headers.insert(Arc::from(hostname.clone()), server_header_list);
             imtdashmap.insert(Arc::from(hostname.clone()), path_map);
         }
 
+        HC_CLIENTS.clear();
         if is_first_run() {
             clone_dashmap_into(&imtdashmap, &config.upstreams);
             mark_not_first_run();
@@ -260,9 +380,358 @@ async fn populate_file_upstreams(config: &mut Configuration, parsed: &Config) {
         }
         info!("Upstream Config:");
         REVERSE_STORE.clear();
+        HASH_RINGS.clear();
+        ROUTE_TABLES.clear();
+        for (hostname, routes) in route_tables {
+            ROUTE_TABLES.insert(hostname, routes);
+        }
         print_upstreams(&config.upstreams, &config.extraparams);
     }
 }
+fn build_rule(m: &MatchConfig) -> Option<RouteRule> {
+    let mut rule = RouteRule::default();
+    for method in m.methods.iter().flatten() {
+        match Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes()) {
+            Ok(method) => rule.methods.push(method),
+            Err(_) => {
+                error!("Invalid method in match rule: {}", method);
+                return None;
+            }
+        }
+    }
+    for header in m.headers.iter().flatten() {
+        rule.headers.push(parse_condition(header, ':')?);
+    }
+    for param in m.query.iter().flatten() {
+        rule.query.push(parse_condition(param, '=')?);
+    }
+    Some(rule)
+}
+
+// "name<sep>value" exact, "name<sep>~regex" regex, "name" presence
+fn parse_condition(condition: &str, sep: char) -> Option<(Arc<str>, ValueMatch)> {
+    let Some((name, value)) = condition.split_once(sep) else {
+        return Some((Arc::from(condition.trim()), ValueMatch::Present));
+    };
+    let name: Arc<str> = Arc::from(name.trim());
+    let value = value.trim();
+    match value.strip_prefix('~') {
+        Some(pattern) => match Regex::new(pattern.trim()) {
+            Ok(re) => Some((name, ValueMatch::Regex(re))),
+            Err(e) => {
+                error!("Invalid regex in match rule {}: {}", condition, e);
+                None
+            }
+        },
+        None => Some((name, ValueMatch::Exact(Arc::from(value)))),
+    }
+}
+
+fn build_retry(path_config: &PathConfig) -> Option<Arc<RetryParams>> {
+    let retries = path_config.retries.filter(|r| *r > 0)?;
+    let mut params = RetryParams {
+        retries,
+        on_connect_failure: false,
+        on_5xx: false,
+        on_timeout: false,
+        non_idempotent: path_config.retry_non_idempotent.unwrap_or(false),
+    };
+    for condition in path_config.retry_on.as_deref().unwrap_or("connect-failure").split(',') {
+        match condition.trim() {
+            "connect-failure" => params.on_connect_failure = true,
+            "5xx" => params.on_5xx = true,
+            "timeout" => params.on_timeout = true,
+            other => warn!("Unknown retry_on condition: {}", other),
+        }
+    }
+    Some(Arc::new(params))
+}
+
+fn build_timeouts(path_config: &PathConfig) -> Option<Arc<TimeoutParams>> {
+    let timeouts = TimeoutParams {
+        connect: path_config.connect_timeout.as_ref().and_then(parse_duration),
+        read: path_config.read_timeout.as_ref().and_then(parse_duration),
+        write: path_config.write_timeout.as_ref().and_then(parse_duration),
+        idle: path_config.idle_timeout.as_ref().and_then(parse_duration),
+        request: path_config.request_timeout.as_ref().and_then(parse_duration),
+    };
+    (timeouts != TimeoutParams::default()).then(|| Arc::new(timeouts))
+}
+
+fn redirect_code(code: Option<u16>) -> u16 {
+    match code {
+        None => 301,
+        Some(c @ (301 | 302 | 303 | 307 | 308)) => c,
+        Some(c) => {
+            warn!("Unsupported redirect code: {}, defaulting to: 301", c);
+            301
+        }
+    }
+}
+
+fn build_redirect(path_config: &PathConfig) -> Option<Arc<RedirectParams>> {
+    let rules = path_config.redirect_map.as_deref().unwrap_or_default();
+    if path_config.redirect_code.is_none() && path_config.redirect_query.is_none() && path_config.redirect_mode.is_none() && rules.is_empty() {
+        return None;
+    }
+    let code = redirect_code(path_config.redirect_code);
+    let replace = match path_config.redirect_mode.as_deref() {
+        None | Some("append") => false,
+        Some("replace") => true,
+        Some(other) => {
+            warn!("Unknown redirect_mode: {}, defaulting to: append", other);
+            false
+        }
+    };
+    let map: Vec<(Pattern, Arc<str>, u16)> = rules
+        .iter()
+        .filter_map(|rule| match Regex::new(&rule.regex) {
+            Ok(re) => Some((Pattern(re), Arc::from(rule.replacement.as_str()), rule.code.map_or(code, |c| redirect_code(Some(c))))),
+            Err(e) => {
+                error!("Invalid redirect_map regex {}: {}", rule.regex, e);
+                None
+            }
+        })
+        .collect();
+    let set = (!map.is_empty())
+        .then(|| RegexSet::new(map.iter().map(|(p, _, _)| p.0.as_str())).ok().map(PatternSet))
+        .flatten();
+    Some(Arc::new(RedirectParams {
+        code,
+        keep_query: path_config.redirect_query.unwrap_or(true),
+        replace,
+        map,
+        set,
+    }))
+}
+
+fn build_mirror(mirror: &MirrorConfig, tls: Option<Arc<UpstreamTls>>) -> Option<Arc<MirrorParams>> {
+    let servers: Vec<(Arc<str>, u16, bool)> = mirror
+        .servers
+        .iter()
+        .filter_map(|server| match parse_server(server) {
+            Some((ip, port, _, scheme)) => Some((Arc::from(ip), port, scheme.and_then(|s| s.flags()).is_some_and(|(ssl, _)| ssl))),
+            None => {
+                warn!("Invalid mirror server: {}", server);
+                None
+            }
+        })
+        .collect();
+    if servers.is_empty() {
+        return None;
+    }
+    Some(Arc::new(MirrorParams {
+        servers,
+        sample: (mirror.percent.unwrap_or(100.0).clamp(0.0, 100.0) * 100.0) as u32,
+        max_body: mirror.max_body.unwrap_or(1024 * 1024),
+        timeout: mirror.timeout.as_ref().and_then(parse_duration).unwrap_or(Duration::from_secs(5)),
+        tls,
+    }))
+}
+
+const COMPRESSIBLE_TYPES: [&str; 9] = [
+    "text/html",
+    "text/css",
+    "text/plain",
+    "text/xml",
+    "text/javascript",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml",
+];
+
+fn build_compression(compression: &CompressionConfig) -> Option<Arc<CompressionParams>> {
+    let mut params = CompressionParams {
+        gzip: compression.algorithms.is_none(),
+        brotli: compression.algorithms.is_none(),
+        zstd: compression.algorithms.is_none(),
+        min_size: compression.min_size.unwrap_or(1024),
+        types: match &compression.types {
+            Some(types) => types.iter().map(|t| Arc::from(t.trim().to_ascii_lowercase())).collect(),
+            None => COMPRESSIBLE_TYPES.iter().map(|t| Arc::from(*t)).collect(),
+        },
+        level: compression.level.unwrap_or(6),
+    };
+    for algorithm in compression.algorithms.iter().flatten() {
+        match algorithm.to_ascii_lowercase().as_str() {
+            "gzip" => params.gzip = true,
+            "br" | "brotli" => params.brotli = true,
+            "zstd" => params.zstd = true,
+            other => warn!("Unknown compression algorithm: {}, ignoring", other),
+        }
+    }
+    (params.gzip || params.brotli || params.zstd).then(|| Arc::new(params))
+}
+
+fn build_cache(cache: &CacheConfig) -> Arc<CacheParams> {
+    Arc::new(CacheParams {
+        ttl: cache.ttl.as_ref().and_then(parse_duration),
+        ttl_override: cache.ttl_override.unwrap_or(false),
+        stale_while_revalidate: cache.stale_while_revalidate.as_ref().and_then(parse_duration).unwrap_or_default(),
+        max_body: cache.max_body.unwrap_or(1024 * 1024),
+        lock_timeout: cache.lock_timeout.as_ref().and_then(parse_duration).unwrap_or(Duration::from_secs(5)),
+    })
+}
+
+fn build_error_pages(config: &ErrorPagesConfig) -> Arc<ErrorPages> {
+    let mut pages = Vec::new();
+    for (status, page) in config.pages.iter().flatten() {
+        let content = match page.strip_prefix("file:") {
+            Some(path) => match fs::read_to_string(path.trim()) {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("Can't read error page {}: {}", path, e);
+                    continue;
+                }
+            },
+            None => page.clone(),
+        };
+        let content: Arc<str> = Arc::from(content);
+        for range in parse_status_ranges(std::slice::from_ref(status)) {
+            pages.push((range, content.clone()));
+        }
+    }
+    // Single codes before ranges, so "503" wins over "5xx"
+    pages.sort_by_key(|((from, to), _)| to.saturating_sub(*from));
+    let json = match config.format.as_deref() {
+        None | Some("html") => false,
+        Some("json") => true,
+        Some(other) => {
+            warn!("Unknown error page format: {}, defaulting to: html", other);
+            false
+        }
+    };
+    Arc::new(ErrorPages {
+        pages,
+        json,
+        intercept_5xx: config.intercept_5xx.unwrap_or(false),
+    })
+}
+
+fn build_rewrite(path_config: &PathConfig) -> Option<Arc<UriRewrite>> {
+    let regex = path_config.rewrite.as_ref().and_then(|r| match Regex::new(&r.regex) {
+        Ok(re) => Some((Pattern(re), Arc::from(r.replacement.as_str()))),
+        Err(e) => {
+            error!("Invalid rewrite regex {}: {}", r.regex, e);
+            None
+        }
+    });
+    let rewrite = UriRewrite {
+        strip_prefix: path_config
+            .strip_prefix
+            .as_deref()
+            .map(|p| p.trim_end_matches('/'))
+            .filter(|p| !p.is_empty())
+            .map(Arc::from),
+        regex,
+        add_prefix: path_config.add_prefix.as_deref().map(|p| p.trim_end_matches('/')).filter(|p| !p.is_empty()).map(Arc::from),
+    };
+    (rewrite.strip_prefix.is_some() || rewrite.regex.is_some() || rewrite.add_prefix.is_some()).then(|| Arc::new(rewrite))
+}
+
+// "500ms", "30s", "5m", "1h" or plain seconds
+pub fn parse_duration(value: &DurationValue) -> Option<Duration> {
+    let text = match value {
+        DurationValue::Seconds(secs) => return Some(Duration::from_secs(*secs)),
+        DurationValue::Text(text) => text.trim(),
+    };
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let parsed = number.parse::<u64>().ok().and_then(|n| match unit.trim() {
+        "ms" => Some(Duration::from_millis(n)),
+        "" | "s" => Some(Duration::from_secs(n)),
+        "m" => n.checked_mul(60).map(Duration::from_secs),
+        "h" => n.checked_mul(3600).map(Duration::from_secs),
+        _ => None,
+    });
+    if parsed.is_none() {
+        warn!("Invalid duration: {}, ignoring", text);
+    }
+    parsed
+}
+
+fn build_healthcheck(hc: &Option<HealthcheckConfig>) -> (Option<bool>, Option<Arc<HealthParams>>) {
+    let hc = match hc {
+        None => return (None, None),
+        Some(HealthcheckConfig::Enabled(enabled)) => return (Some(*enabled), None),
+        Some(HealthcheckConfig::Custom(hc)) => hc,
+    };
+    let body_regex = hc.body_regex.as_ref().and_then(|r| match Regex::new(r) {
+        Ok(re) => Some(Pattern(re)),
+        Err(e) => {
+            error!("Invalid healthcheck body_regex {}: {}", r, e);
+            None
+        }
+    });
+    let mut headers = Vec::new();
+    for header in hc.headers.iter().flatten() {
+        if let Some((key, val)) = header.split_once(':') {
+            headers.push((Arc::from(key.trim()), Arc::from(val.trim())));
+        }
+    }
+    let mut method = hc.method.as_ref().map(|m| m.to_uppercase());
+    if method.is_none() && (hc.body_contains.is_some() || body_regex.is_some()) {
+        method = Some("GET".to_string());
+    }
+    let params = HealthParams {
+        path: hc.path.as_deref().map(Arc::from),
+        method: method.as_deref().map(Arc::from),
+        expected_status: parse_status_ranges(hc.expected_status.as_deref().unwrap_or_default()),
+        body_contains: hc.body_contains.as_deref().map(Arc::from),
+        body_regex,
+        headers,
+        timeout: hc.timeout.map(Duration::from_secs),
+        rise: hc.rise.unwrap_or(1).max(1),
+        fall: hc.fall.unwrap_or(1).max(1),
+    };
+    (Some(hc.enabled.unwrap_or(true)), Some(Arc::new(params)))
+}
+
+// Status codes and ranges: "200", "200-299" or "2xx", classes are 1xx to 5xx
+pub fn parse_status_ranges(list: &[String]) -> Vec<(u16, u16)> {
+    let mut ranges = Vec::new();
+    for item in list {
+        let item = item.trim().to_ascii_lowercase();
+        let range = if let Some(class) = item.strip_suffix("xx") {
+            class.parse::<u16>().ok().filter(|c| (1..=5).contains(c)).map(|c| (c * 100, c * 100 + 99))
+        } else if let Some((from, to)) = item.split_once('-') {
+            from.trim().parse::<u16>().ok().zip(to.trim().parse::<u16>().ok())
+        } else {
+            item.parse::<u16>().ok().map(|code| (code, code))
+        };
+        match range {
+            Some(r) => ranges.push(r),
+            None => warn!("Invalid status code or range: {}", item),
+        }
+    }
+    ranges
+}
+
+// Server entry: "10.0.0.1:8000", "10.0.0.1:8000 weight=5" or with scheme "h2c://10.0.0.1:50051"
+fn parse_server(server: &str) -> Option<(&str, u16, u32, Option<UpstreamProtocol>)> {
+    let mut parts = server.split_whitespace();
+    let mut address = parts.next()?;
+    let mut protocol = None;
+    if let Some((scheme, rest)) = address.split_once("://") {
+        protocol = Some(UpstreamProtocol::from_str(scheme));
+        address = rest.trim_end_matches('/');
+    }
+    let (ip, port_str) = address.rsplit_once(':')?;
+    let port = port_str.parse::<u16>().ok()?;
+    let mut weight = 1;
+    for option in parts {
+        match option.split_once('=') {
+            Some(("weight", w)) => match w.parse::<u32>() {
+                Ok(w) if w > 0 => weight = w,
+                _ => warn!("Invalid weight in server entry: {}, defaulting to 1", server),
+            },
+            _ => warn!("Unknown option in server entry: {}", server),
+        }
+    }
+    Some((ip, port, weight, protocol))
+}
+
 pub fn parce_main_config(path: &str) -> AppConfig {
     let data = fs::read_to_string(path).unwrap();
     let mut cfo: AppConfig = noyalib::from_str(&data).expect("Failed to parse main config file");
@@ -318,16 +787,52 @@ fn parce_tls_grades(what: Option<String>) -> Option<String> {
     }
 }
 
-pub fn build_headers(path_config: &Option<Vec<String>>, _config: &Configuration, hl: &mut Vec<(String, Arc<str>)>) {
+// "Name: value" appends on responses and replaces on upstream requests, as before. An operation word
+// and a "status" condition for client headers can precede the name: "status 5xx set Retry-After: 30", "remove Server".
+pub fn build_headers(path_config: &Option<Vec<String>>, _config: &Configuration, default_op: HeaderOp, hl: &mut Vec<HeaderRule>) {
     if let Some(headers) = &path_config {
         for header in headers {
-            if let Some((key, val)) = header.split_once(':') {
-                hl.push((key.trim().to_string(), Arc::from(val.trim())));
+            match parse_header_rule(header, default_op) {
+                Some(rule) => hl.push(rule),
+                None => warn!("Invalid header entry: {}, ignoring", header),
             }
         }
     }
 }
 
+fn parse_header_rule(header: &str, default_op: HeaderOp) -> Option<HeaderRule> {
+    let (head, value) = match header.split_once(':') {
+        Some((head, value)) => (head, Some(value.trim())),
+        None => (header, None),
+    };
+    let mut words: Vec<&str> = head.split_whitespace().collect();
+    let name = words.pop()?;
+    let mut op = default_op;
+    let mut status = Vec::new();
+    let mut words = words.into_iter();
+    while let Some(word) = words.next() {
+        match word.to_ascii_lowercase().as_str() {
+            "append" => op = HeaderOp::Append,
+            "set" => op = HeaderOp::Set,
+            "remove" => op = HeaderOp::Remove,
+            "status" => {
+                let codes: Vec<String> = words.next()?.split(',').map(str::to_string).collect();
+                status = parse_status_ranges(&codes);
+            }
+            _ => return None,
+        }
+    }
+    if op != HeaderOp::Remove && value.is_none() {
+        return None;
+    }
+    Some(HeaderRule {
+        name: name.to_string(),
+        value: Arc::from(value.unwrap_or_default()),
+        op,
+        status,
+    })
+}
+
 fn log_builder(conf: &AppConfig, location: &Option<String>) {
     let log_level = match conf.log_level.as_str() {
         "info" => LevelFilter::Info,
@@ -359,3 +864,119 @@ fn log_builder(conf: &AppConfig, location: &Option<String>) {
         log4rs::init_config(config).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn server_entries() {
+        let cases = [
+            ("10.0.0.1:8000", Some(("10.0.0.1", 8000, 1, None))),
+            ("10.0.0.1:8000 weight=5", Some(("10.0.0.1", 8000, 5, None))),
+            ("  backend.local:80   weight=2  ", Some(("backend.local", 80, 2, None))),
+            ("10.0.0.1:8000 weight=0", Some(("10.0.0.1", 8000, 1, None))),
+            ("10.0.0.1:8000 weight=heavy", Some(("10.0.0.1", 8000, 1, None))),
+            ("10.0.0.1:8000 backup", Some(("10.0.0.1", 8000, 1, None))),
+            ("h2://10.0.0.1:443", Some(("10.0.0.1", 443, 1, Some(UpstreamProtocol::H2)))),
+            ("HTTP://10.0.0.1:80/ weight=3", Some(("10.0.0.1", 80, 3, Some(UpstreamProtocol::Http)))),
+            ("gopher://10.0.0.1:70", Some(("10.0.0.1", 70, 1, Some(UpstreamProtocol::Auto)))),
+            ("10.0.0.1", None),
+            ("10.0.0.1:65536", None),
+            ("", None),
+            ("h2c://10.0.0.1", None),
+        ];
+        for (entry, expected) in cases {
+            assert_eq!(parse_server(entry), expected, "{}", entry);
+        }
+    }
+
+    #[test]
+    fn status_ranges() {
+        let cases = [
+            (vec!["200"], vec![(200, 200)]),
+            (vec!["2xx"], vec![(200, 299)]),
+            (vec![" 3XX "], vec![(300, 399)]),
+            (vec!["200-204"], vec![(200, 204)]),
+            (vec!["200 - 204", "404"], vec![(200, 204), (404, 404)]),
+            (vec!["ok", "2xx-3xx", "200-", "-1"], vec![]),
+            (vec!["0xx", "9xx", "700xx", "1000xx"], vec![]),
+            (vec!["1xx", "5xx"], vec![(100, 199), (500, 599)]),
+            (vec![], vec![]),
+        ];
+        for (list, expected) in cases {
+            assert_eq!(parse_status_ranges(&strings(&list)), expected, "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        let cases = [
+            (DurationValue::Seconds(30), Some(Duration::from_secs(30))),
+            (DurationValue::Text("500ms".into()), Some(Duration::from_millis(500))),
+            (DurationValue::Text(" 30s ".into()), Some(Duration::from_secs(30))),
+            (DurationValue::Text("15".into()), Some(Duration::from_secs(15))),
+            (DurationValue::Text("5m".into()), Some(Duration::from_secs(300))),
+            (DurationValue::Text("2h".into()), Some(Duration::from_secs(7200))),
+            (DurationValue::Text("1.5s".into()), None),
+            (DurationValue::Text("10d".into()), None),
+            (DurationValue::Text(format!("{}h", u64::MAX)), None),
+            (DurationValue::Text("99999999999999999999s".into()), None),
+            (DurationValue::Text("ms".into()), None),
+            (DurationValue::Text("".into()), None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_duration(&value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn rewrite_settings() {
+        assert!(build_rewrite(&PathConfig::default()).is_none());
+        let config = PathConfig {
+            strip_prefix: Some("/api/".into()),
+            add_prefix: Some("/".into()),
+            ..PathConfig::default()
+        };
+        let rewrite = build_rewrite(&config).unwrap();
+        assert_eq!(rewrite.strip_prefix.as_deref(), Some("/api"));
+        assert_eq!(rewrite.add_prefix, None);
+        let config = PathConfig {
+            rewrite: Some(RewriteConfig {
+                regex: "^/(v[0-9]+".into(),
+                replacement: "/$1".into(),
+            }),
+            ..PathConfig::default()
+        };
+        assert!(build_rewrite(&config).is_none());
+    }
+
+    #[test]
+    fn header_rules() {
+        let cases = [
+            ("X-Frame-Options: DENY", Some(("X-Frame-Options", "DENY", HeaderOp::Append, vec![]))),
+            ("set Cache-Control: no-store", Some(("Cache-Control", "no-store", HeaderOp::Set, vec![]))),
+            ("REMOVE Server", Some(("Server", "", HeaderOp::Remove, vec![]))),
+            (
+                "status 5xx,404 set Retry-After: 30",
+                Some(("Retry-After", "30", HeaderOp::Set, vec![(500, 599), (404, 404)])),
+            ),
+            ("X-Upstream: $upstream_addr:80", Some(("X-Upstream", "$upstream_addr:80", HeaderOp::Append, vec![]))),
+            ("X-Empty:", Some(("X-Empty", "", HeaderOp::Append, vec![]))),
+            ("set X-Missing-Value", None),
+            ("replace X-Name: value", None),
+            ("status", None),
+            (": value", None),
+            ("", None),
+        ];
+        for (entry, expected) in cases {
+            let rule = parse_header_rule(entry, HeaderOp::Append);
+            let rule = rule.as_ref().map(|r| (r.name.as_str(), &*r.value, r.op, r.status.clone()));
+            assert_eq!(rule, expected, "{}", entry);
+        }
+    }
+}
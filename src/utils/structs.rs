@@ -1,13 +1,80 @@
 use dashmap::DashMap;
+use regex::{Regex, RegexSet};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::atomic::AtomicUsize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub type UpstreamsDashMap = DashMap<Arc<str>, DashMap<Arc<str>, (Vec<Arc<InnerMap>>, AtomicUsize)>>;
+pub type UpstreamsDashMap = DashMap<Arc<str>, DashMap<RouteKey, (Vec<Arc<InnerMap>>, AtomicUsize)>>;
 
 pub type UpstreamsIdMap = DashMap<String, Arc<InnerMap>>;
-pub type Headers = DashMap<Arc<str>, DashMap<Arc<str>, Vec<(String, Arc<str>)>>>;
+pub type Headers = DashMap<Arc<str>, DashMap<RouteKey, Vec<HeaderRule>>>;
+
+// Backend pool of a host: a configured path, optionally narrowed to one of its match rules and traffic groups
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RouteKey {
+    pub path: Arc<str>,
+    pub rule: Option<usize>,
+    pub group: Option<Arc<str>>,
+}
+
+impl RouteKey {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: Arc::from(path),
+            rule: None,
+            group: None,
+        }
+    }
+
+    pub fn with_rule(&self, n: usize) -> Self {
+        Self { rule: Some(n), ..self.clone() }
+    }
+
+    pub fn with_group(&self, name: &str) -> Self {
+        Self {
+            group: Some(Arc::from(name)),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for RouteKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)?;
+        match (self.rule, &self.group) {
+            (Some(n), Some(group)) => write!(f, " (rule {}, group {})", n, group),
+            (Some(n), None) => write!(f, " (rule {})", n),
+            (None, Some(group)) => write!(f, " (group {})", group),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderOp {
+    Append,
+    Set,
+    Remove,
+}
+
+// One entry of client_headers/server_headers: "[status 5xx,404] [append|set|remove] Name[: value]"
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub name: String,
+    pub value: Arc<str>,
+    pub op: HeaderOp,
+    pub status: Vec<(u16, u16)>,
+}
+
+impl HeaderRule {
+    pub fn matches(&self, status: u16) -> bool {
+        self.status.is_empty() || self.status.iter().any(|(from, to)| (*from..=*to).contains(&status))
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct Extraparams {
@@ -16,6 +83,14 @@ pub struct Extraparams {
     pub authentication: Option<Arc<InnerAuth>>,
     pub rate_limit: Option<isize>,
     pub x4xx_limit: Option<u32>,
+    pub host_patterns: Arc<Vec<HostPattern>>,
+}
+
+// Wildcard "*.example.com" or regex "~^(.+)\.example\.com$" upstream host, compiled once per config
+#[derive(Debug)]
+pub struct HostPattern {
+    pub regex: Regex,
+    pub key: Arc<str>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -29,6 +104,10 @@ pub struct GlobalServiceMapping {
     pub x4xx_limit: Option<u32>,
     pub client_headers: Option<Vec<String>>,
     pub server_headers: Option<Vec<String>>,
+    pub lb_method: Option<String>,
+    pub hash_on: Option<String>,
+    pub upstream_protocol: Option<String>,
+    pub upstream_sni: Option<String>,
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
@@ -70,9 +149,10 @@ pub struct Config {
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct HostConfig {
-    pub paths: HashMap<String, PathConfig>,
+    pub paths: HashMap<String, PathRules>,
     pub rate_limit: Option<isize>,
     pub x4xx_limit: Option<u32>,
+    pub error_pages: Option<ErrorPagesConfig>,
 }
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Auth {
@@ -81,6 +161,137 @@ pub struct Auth {
     #[serde(rename = "data")]
     pub auth_cred: Option<String>,
 }
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum HealthcheckConfig {
+    Enabled(bool),
+    Custom(HealthCheck),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct HealthCheck {
+    pub enabled: Option<bool>,
+    pub path: Option<String>,
+    pub method: Option<String>,
+    pub expected_status: Option<Vec<String>>,
+    pub body_contains: Option<String>,
+    pub body_regex: Option<String>,
+    pub headers: Option<Vec<String>>,
+    pub timeout: Option<u64>,
+    pub rise: Option<u32>,
+    pub fall: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct OutlierDetection {
+    pub consecutive_5xx: Option<u32>,
+    pub consecutive_connect_failures: Option<u32>,
+    pub base_ejection_time: Option<u64>,
+    pub max_ejection_time: Option<u64>,
+    pub max_ejection_percent: Option<u8>,
+}
+
+// A path is either a single config or a list of configs with `match` rules, evaluated in order
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PathRules {
+    Single(Box<PathConfig>),
+    List(Vec<PathConfig>),
+}
+
+impl PathRules {
+    pub fn entries(&self) -> &[PathConfig] {
+        match self {
+            PathRules::Single(config) => std::slice::from_ref(config.as_ref()),
+            PathRules::List(configs) => configs,
+        }
+    }
+}
+
+// Headers as "Name: value", "Name: ~regex" or "Name" for presence, query params the same way with "="
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MatchConfig {
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+    pub query: Option<Vec<String>>,
+}
+
+// Durations are either plain seconds or strings like "500ms", "30s", "5m"
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum DurationValue {
+    Seconds(u64),
+    Text(String),
+}
+
+// Named pool of servers sharing the traffic of a path by weight, like stable and canary releases
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct UpstreamGroup {
+    pub weight: u32,
+    pub servers: Vec<String>,
+}
+
+// Requests with path matching `regex` are redirected to `replacement`, which may use capture groups
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RedirectRule {
+    pub regex: String,
+    pub replacement: String,
+    pub code: Option<u16>,
+}
+
+// Copies of sampled requests are sent to these servers, responses are discarded
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MirrorConfig {
+    pub servers: Vec<String>,
+    pub percent: Option<f64>,
+    pub max_body: Option<usize>,
+    pub timeout: Option<DurationValue>,
+}
+
+// Responses of listed types and at least min_size bytes are compressed with the enabled algorithm
+// the client prefers by its Accept-Encoding q-values
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct CompressionConfig {
+    pub algorithms: Option<Vec<String>>,
+    pub min_size: Option<usize>,
+    pub types: Option<Vec<String>>,
+    pub level: Option<u32>,
+}
+
+// Responses of GET requests are cached by host and URI, following Cache-Control, Expires and Vary of the upstream
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct CacheConfig {
+    pub ttl: Option<DurationValue>,
+    pub ttl_override: Option<bool>,
+    pub stale_while_revalidate: Option<DurationValue>,
+    pub max_body: Option<usize>,
+    pub lock_timeout: Option<DurationValue>,
+}
+
+// Status codes or ranges mapped to inline templates or "file:/path/to/page.html", `format: json` for API hosts
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ErrorPagesConfig {
+    pub pages: Option<BTreeMap<String, String>>,
+    pub format: Option<String>,
+    pub intercept_5xx: Option<bool>,
+}
+
+// Regex applied to the request path, `replacement` may refer to capture groups as $1, $2 or $name
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RewriteConfig {
+    pub regex: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct UpstreamTlsConfig {
+    pub verify: Option<bool>,
+    pub ca_file: Option<String>,
+    pub sni: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PathConfig {
     pub servers: Vec<String>,
@@ -89,9 +300,41 @@ pub struct PathConfig {
     pub server_headers: Option<Vec<String>>,
     pub rate_limit: Option<isize>,
     pub x4xx_limit: Option<u32>,
-    pub healthcheck: Option<bool>,
+    pub healthcheck: Option<HealthcheckConfig>,
     pub redirect_to: Option<String>,
+    pub redirect_code: Option<u16>,
+    pub redirect_query: Option<bool>,
+    pub redirect_mode: Option<String>,
+    pub redirect_map: Option<Vec<RedirectRule>>,
     pub authorization: Option<Auth>,
+    pub lb_method: Option<String>,
+    pub hash_on: Option<String>,
+    pub outlier_detection: Option<OutlierDetection>,
+    pub upstream_protocol: Option<String>,
+    pub upstream_sni: Option<String>,
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+    pub retries: Option<u32>,
+    pub retry_on: Option<String>,
+    pub retry_non_idempotent: Option<bool>,
+    pub connect_timeout: Option<DurationValue>,
+    pub read_timeout: Option<DurationValue>,
+    pub write_timeout: Option<DurationValue>,
+    pub idle_timeout: Option<DurationValue>,
+    pub request_timeout: Option<DurationValue>,
+    pub priority: Option<i32>,
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+    pub rewrite: Option<RewriteConfig>,
+    pub host_header: Option<String>,
+    pub groups: Option<BTreeMap<String, UpstreamGroup>>,
+    pub mirror: Option<MirrorConfig>,
+    pub proxy_protocol: Option<bool>,
+    pub compression: Option<CompressionConfig>,
+    pub cache: Option<CacheConfig>,
+    pub error_pages: Option<ErrorPagesConfig>,
+    pub pin_on: Option<String>,
+    #[serde(rename = "match")]
+    pub match_rule: Option<MatchConfig>,
 }
 #[derive(Debug, Default)]
 pub struct Configuration {
@@ -108,6 +351,8 @@ pub struct Configuration {
 pub struct AppConfig {
     pub hc_interval: u16,
     pub hc_method: String,
+    pub hc_timeout: Option<u64>,
+    pub hc_concurrency: Option<usize>,
     pub upstreams_conf: String,
     pub log_level: String,
     pub access_log: Option<String>,
@@ -133,6 +378,12 @@ pub struct AppConfig {
     pub tcp_keepalive_idle: Option<u64>,
     pub tcp_keepalive_interval: Option<u64>,
     pub tcp_keepalive_count: Option<usize>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub proxy_protocol_http: Option<bool>,
+    pub proxy_protocol_tls: Option<bool>,
+    pub request_id_header: Option<String>,
+    pub request_id_format: Option<String>,
+    pub cache_size: Option<u64>,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
@@ -141,18 +392,418 @@ pub struct InnerAuth {
     pub auth_cred: Arc<str>,
 }
 
+// Runtime counters of a single backend. Shared between the full and the live upstream maps,
+// so it survives health check rebuilds. Never takes part in comparisons of upstream lists.
+#[derive(Debug, Default)]
+pub struct BackendState {
+    pub current_weight: AtomicI64,
+    pub in_flight: AtomicUsize,
+    pub ewma_latency: AtomicU64,
+    pub hc_successes: AtomicU32,
+    pub hc_failures: AtomicU32,
+    pub down: AtomicBool,
+    pub probed: AtomicBool,
+    pub last_change: AtomicU64,
+    pub consecutive_5xx: AtomicU32,
+    pub consecutive_connect_failures: AtomicU32,
+    pub ejected: AtomicBool,
+    pub ejected_until: AtomicU64,
+    pub ejections: AtomicU32,
+    pub tls_detected: AtomicU8,
+}
+
+impl BackendState {
+    // Exponentially weighted moving average of upstream latency in microseconds, alpha = 0.3
+    pub fn observe_latency(&self, latency: Duration) {
+        let sample = latency.as_micros().min(u64::MAX as u128) as u64;
+        let old = self.ewma_latency.load(Ordering::Relaxed);
+        let new = if old == 0 { sample } else { (old / 10) * 7 + (sample / 10) * 3 };
+        self.ewma_latency.store(new.max(1), Ordering::Relaxed);
+    }
+
+    // Counts consecutive probe results, flips the backend only after `rise` successes or `fall` failures in a row.
+    // The first probe of a new backend sets its health right away. Returns current health and whether it has just changed.
+    pub fn record_probe(&self, ok: bool, rise: u32, fall: u32) -> (bool, bool) {
+        let down = self.down.load(Ordering::Relaxed);
+        let first = !self.probed.swap(true, Ordering::Relaxed);
+        let flip = if ok {
+            self.hc_failures.store(0, Ordering::Relaxed);
+            let n = self.hc_successes.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+            down && (first || n >= rise)
+        } else {
+            self.hc_successes.store(0, Ordering::Relaxed);
+            let n = self.hc_failures.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+            !down && (first || n >= fall)
+        };
+        if flip {
+            if !down {
+                // The backend may come back with another protocol, detect it again
+                self.tls_detected.store(0, Ordering::Relaxed);
+            }
+            self.down.store(!down, Ordering::Relaxed);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            self.last_change.store(now, Ordering::Relaxed);
+        }
+        (down == flip, flip)
+    }
+
+    // Cached result of TLS/HTTP2 detection as (is_ssl, is_http2), None until detected
+    pub fn tls_detected(&self) -> Option<(bool, bool)> {
+        match self.tls_detected.load(Ordering::Relaxed) {
+            0 => None,
+            v => Some((v & 0b10 != 0, v & 0b100 != 0)),
+        }
+    }
+
+    pub fn cache_tls(&self, is_ssl: bool, is_http2: bool) {
+        self.tls_detected.store(1 | ((is_ssl as u8) << 1) | ((is_http2 as u8) << 2), Ordering::Relaxed);
+    }
+
+    // Ejected backends stay in the live pools, requests skip them until the ejection period is over
+    pub fn is_ejected(&self) -> bool {
+        self.ejected.load(Ordering::Relaxed) && now_millis() < self.ejected_until.load(Ordering::Relaxed)
+    }
+
+    // Every next ejection lasts twice longer than the previous one, up to max_ejection_time
+    pub fn eject(&self, params: &OutlierParams) -> Duration {
+        let n = self.ejections.fetch_add(1, Ordering::Relaxed).min(16);
+        let period = params.base_ejection_time.saturating_mul(1 << n).min(params.max_ejection_time);
+        self.ejected_until.store(now_millis() + period.as_millis() as u64, Ordering::Relaxed);
+        self.ejected.store(true, Ordering::Relaxed);
+        self.consecutive_5xx.store(0, Ordering::Relaxed);
+        self.consecutive_connect_failures.store(0, Ordering::Relaxed);
+        period
+    }
+
+    // Successful response, clears an expired ejection. The ejection multiplier is forgotten after max_ejection_time of good behaviour
+    pub fn record_success(&self, params: &OutlierParams) {
+        if self.consecutive_5xx.load(Ordering::Relaxed) != 0 {
+            self.consecutive_5xx.store(0, Ordering::Relaxed);
+        }
+        if self.consecutive_connect_failures.load(Ordering::Relaxed) != 0 {
+            self.consecutive_connect_failures.store(0, Ordering::Relaxed);
+        }
+        if self.is_ejected() {
+            return;
+        }
+        if self.ejected.load(Ordering::Relaxed) {
+            self.ejected.store(false, Ordering::Relaxed);
+        }
+        if self.ejections.load(Ordering::Relaxed) != 0 && now_millis() > self.ejected_until.load(Ordering::Relaxed) + params.max_ejection_time.as_millis() as u64 {
+            self.ejections.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+impl PartialEq for BackendState {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+impl Eq for BackendState {}
+impl Hash for BackendState {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LbMethod {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    P2cEwma,
+    Random,
+    RingHash,
+}
+
+// Request attribute used as a key for ring_hash balancing
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub enum HashOn {
+    #[default]
+    Ip,
+    Uri,
+    Header(Arc<str>),
+    Cookie(Arc<str>),
+}
+
+impl HashOn {
+    pub fn from_str(s: &str) -> Self {
+        match s.split_once(':') {
+            Some(("header", name)) if !name.trim().is_empty() => HashOn::Header(Arc::from(name.trim())),
+            Some(("cookie", name)) if !name.trim().is_empty() => HashOn::Cookie(Arc::from(name.trim())),
+            _ => match s {
+                "ip" => HashOn::Ip,
+                "uri" => HashOn::Uri,
+                _ => {
+                    log::warn!("Unknown hash_on: {}, defaulting to: ip", s);
+                    HashOn::Ip
+                }
+            },
+        }
+    }
+}
+
+// Protocol spoken to upstream servers, `Auto` is detected by health checks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpstreamProtocol {
+    #[default]
+    Auto,
+    Http,
+    H2c,
+    Https,
+    H2,
+}
+
+impl UpstreamProtocol {
+    pub fn from_str(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => UpstreamProtocol::Auto,
+            "http" | "http1" => UpstreamProtocol::Http,
+            "h2c" => UpstreamProtocol::H2c,
+            "https" => UpstreamProtocol::Https,
+            "h2" => UpstreamProtocol::H2,
+            _ => {
+                log::warn!("Unknown upstream_protocol: {}, defaulting to: auto", s);
+                UpstreamProtocol::Auto
+            }
+        }
+    }
+
+    // (is_ssl, is_http2) for explicitly configured protocols
+    pub fn flags(&self) -> Option<(bool, bool)> {
+        match self {
+            UpstreamProtocol::Auto => None,
+            UpstreamProtocol::Http => Some((false, false)),
+            UpstreamProtocol::H2c => Some((false, true)),
+            UpstreamProtocol::Https => Some((true, false)),
+            UpstreamProtocol::H2 => Some((true, true)),
+        }
+    }
+}
+
+impl LbMethod {
+    pub fn from_str(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "round_robin" => LbMethod::RoundRobin,
+            "least_conn" => LbMethod::LeastConn,
+            "p2c_ewma" => LbMethod::P2cEwma,
+            "random" => LbMethod::Random,
+            "ring_hash" | "consistent_hash" => LbMethod::RingHash,
+            _ => {
+                log::warn!("Unknown lb_method: {}, defaulting to: round_robin", s);
+                LbMethod::RoundRobin
+            }
+        }
+    }
+}
+
+// Compiled regex, compared and hashed by its source, so it can be a part of InnerMap
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+impl Eq for Pattern {}
+impl Hash for Pattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state);
+    }
+}
+
+// Compiled regex set, compared and hashed by its sources
+#[derive(Debug, Clone)]
+pub struct PatternSet(pub RegexSet);
+
+impl PartialEq for PatternSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.patterns() == other.0.patterns()
+    }
+}
+impl Eq for PatternSet {}
+impl Hash for PatternSet {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.patterns().hash(state);
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct HealthParams {
+    pub path: Option<Arc<str>>,
+    pub method: Option<Arc<str>>,
+    pub expected_status: Vec<(u16, u16)>,
+    pub body_contains: Option<Arc<str>>,
+    pub body_regex: Option<Pattern>,
+    pub headers: Vec<(Arc<str>, Arc<str>)>,
+    pub timeout: Option<Duration>,
+    pub rise: u32,
+    pub fall: u32,
+}
+
+// Retry policy of a path, `retries` is the number of additional attempts, each on another backend
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RetryParams {
+    pub retries: u32,
+    pub on_connect_failure: bool,
+    pub on_5xx: bool,
+    pub on_timeout: bool,
+    pub non_idempotent: bool,
+}
+
+// Upstream timeouts of a path, applied to HttpPeer options
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct TimeoutParams {
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub request: Option<Duration>,
+}
+
+// Redirect behaviour of a path, applied to redirect_to, to_https and the redirect map.
+// The map is checked first with a regex set, so large maps cost a single pass over the path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RedirectParams {
+    pub code: u16,
+    pub keep_query: bool,
+    pub replace: bool,
+    pub map: Vec<(Pattern, Arc<str>, u16)>,
+    pub set: Option<PatternSet>,
+}
+
+impl Default for RedirectParams {
+    fn default() -> Self {
+        Self {
+            code: 301,
+            keep_query: true,
+            replace: false,
+            map: Vec::new(),
+            set: None,
+        }
+    }
+}
+
+impl RedirectParams {
+    // Location and status code of the first map entry matching the path
+    pub fn map_location(&self, path: &str) -> Option<(String, u16)> {
+        let n = self.set.as_ref()?.0.matches(path).into_iter().next()?;
+        let (pattern, replacement, code) = &self.map[n];
+        Some((pattern.0.replace(path, replacement.as_ref()).into_owned(), *code))
+    }
+}
+
+// Mirror targets as (address, port, is_ssl), sample is in hundredths of percent
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MirrorParams {
+    pub servers: Vec<(Arc<str>, u16, bool)>,
+    pub sample: u32,
+    pub max_body: usize,
+    pub timeout: Duration,
+    pub tls: Option<Arc<UpstreamTls>>,
+}
+
+// Content types are lowercase, "text/*" matches the whole group
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CompressionParams {
+    pub gzip: bool,
+    pub brotli: bool,
+    pub zstd: bool,
+    pub min_size: usize,
+    pub types: Vec<Arc<str>>,
+    pub level: u32,
+}
+
+// ttl is used for responses without freshness information, or for all of them with ttl_override
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheParams {
+    pub ttl: Option<Duration>,
+    pub ttl_override: bool,
+    pub stale_while_revalidate: Duration,
+    pub max_body: usize,
+    pub lock_timeout: Duration,
+}
+
+// Templates are loaded once per config, the first matching range wins
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ErrorPages {
+    pub pages: Vec<((u16, u16), Arc<str>)>,
+    pub json: bool,
+    pub intercept_5xx: bool,
+}
+
+impl ErrorPages {
+    pub fn page(&self, status: u16) -> Option<&str> {
+        self.pages.iter().find(|((from, to), _)| (*from..=*to).contains(&status)).map(|(_, page)| page.as_ref())
+    }
+}
+
+// Settings of a path entry which don't depend on its upstreams, so they apply even when all of them are down
+#[derive(Debug, Default)]
+pub struct PathSettings {
+    pub error_pages: Option<Arc<ErrorPages>>,
+    pub redirect_to: Option<Arc<str>>,
+    pub redirect: Option<Arc<RedirectParams>>,
+}
+
+// Request path changes before forwarding, applied in order: strip_prefix, regex, add_prefix
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UriRewrite {
+    pub strip_prefix: Option<Arc<str>>,
+    pub regex: Option<(Pattern, Arc<str>)>,
+    pub add_prefix: Option<Arc<str>>,
+}
+
+// TLS settings towards upstreams, files are loaded into caches of tls::upstream
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UpstreamTls {
+    pub verify: bool,
+    pub ca_file: Option<Arc<str>>,
+    pub client_cert: Option<Arc<str>>,
+    pub client_key: Option<Arc<str>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutlierParams {
+    pub consecutive_5xx: u32,
+    pub consecutive_connect_failures: u32,
+    pub base_ejection_time: Duration,
+    pub max_ejection_time: Duration,
+    pub max_ejection_percent: u8,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct InnerMap {
     pub address: Arc<str>,
     pub port: u16,
+    pub weight: u32,
     pub is_ssl: bool,
     pub is_http2: bool,
     pub to_https: bool,
     pub rate_limit: Option<isize>,
     pub x4xx_limit: Option<u32>,
     pub healthcheck: Option<bool>,
-    pub redirect_to: Option<Arc<str>>,
+    pub hc_params: Option<Arc<HealthParams>>,
     pub authorization: Option<Arc<InnerAuth>>,
+    pub lb_method: LbMethod,
+    pub hash_on: HashOn,
+    pub outlier: Option<Arc<OutlierParams>>,
+    pub protocol: UpstreamProtocol,
+    pub sni: Option<Arc<str>>,
+    pub tls: Option<Arc<UpstreamTls>>,
+    pub retry: Option<Arc<RetryParams>>,
+    pub timeouts: Option<Arc<TimeoutParams>>,
+    pub rewrite: Option<Arc<UriRewrite>>,
+    pub host_header: Option<Arc<str>>,
+    pub mirror: Option<Arc<MirrorParams>>,
+    pub proxy_protocol: bool,
+    pub compression: Option<Arc<CompressionParams>>,
+    pub cache: Option<Arc<CacheParams>>,
+    pub state: Arc<BackendState>,
 }
 
 #[allow(dead_code)]
@@ -161,14 +812,30 @@ impl InnerMap {
         Self {
             address: Arc::from("127.0.0.1"),
             port: Default::default(),
+            weight: 1,
             is_ssl: Default::default(),
             is_http2: Default::default(),
             to_https: Default::default(),
             rate_limit: Default::default(),
             x4xx_limit: Default::default(),
             healthcheck: Default::default(),
-            redirect_to: Default::default(),
+            hc_params: Default::default(),
             authorization: Default::default(),
+            lb_method: Default::default(),
+            hash_on: Default::default(),
+            outlier: Default::default(),
+            protocol: Default::default(),
+            sni: Default::default(),
+            tls: Default::default(),
+            retry: Default::default(),
+            timeouts: Default::default(),
+            rewrite: Default::default(),
+            host_header: Default::default(),
+            mirror: Default::default(),
+            proxy_protocol: Default::default(),
+            compression: Default::default(),
+            cache: Default::default(),
+            state: Default::default(),
         }
     }
 }
@@ -177,6 +844,9 @@ impl InnerMap {
 pub struct InnerMapForJson {
     pub address: String,
     pub port: u16,
+    pub weight: u32,
+    pub in_flight: usize,
+    pub ewma_latency_us: u64,
     pub is_ssl: bool,
     pub is_http2: bool,
     pub to_https: bool,
@@ -189,3 +859,99 @@ pub struct UpstreamSnapshotForJson {
     pub backends: Vec<InnerMapForJson>,
     pub requests: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A backend which already went through its first probe
+    fn probed() -> BackendState {
+        let state = BackendState::default();
+        state.probed.store(true, Ordering::Relaxed);
+        state
+    }
+
+    #[test]
+    fn first_probe_sets_the_state_of_a_new_backend() {
+        let failing = BackendState::default();
+        assert_eq!(failing.record_probe(false, 2, 3), (false, true));
+        assert_eq!(failing.record_probe(true, 2, 3), (false, false));
+        assert_eq!(failing.record_probe(true, 2, 3), (true, true));
+        let passing = BackendState::default();
+        assert_eq!(passing.record_probe(true, 2, 3), (true, false));
+        assert_eq!(passing.record_probe(false, 2, 3), (true, false));
+    }
+
+    #[test]
+    fn record_probe_needs_fall_failures_to_go_down() {
+        let state = probed();
+        assert_eq!(state.record_probe(false, 2, 3), (true, false));
+        assert_eq!(state.record_probe(false, 2, 3), (true, false));
+        assert_eq!(state.record_probe(false, 2, 3), (false, true));
+        assert_eq!(state.record_probe(false, 2, 3), (false, false));
+    }
+
+    #[test]
+    fn record_probe_needs_rise_successes_to_come_back() {
+        let state = probed();
+        state.down.store(true, Ordering::Relaxed);
+        assert_eq!(state.record_probe(true, 2, 3), (false, false));
+        assert_eq!(state.record_probe(true, 2, 3), (true, true));
+        assert_eq!(state.record_probe(true, 2, 3), (true, false));
+    }
+
+    #[test]
+    fn record_probe_resets_the_streak_on_a_mixed_result() {
+        let state = probed();
+        state.record_probe(false, 1, 2);
+        state.record_probe(true, 1, 2);
+        assert_eq!(state.record_probe(false, 1, 2), (true, false));
+        assert_eq!(state.record_probe(false, 1, 2), (false, true));
+    }
+
+    fn outlier(base: u64, max: u64) -> OutlierParams {
+        OutlierParams {
+            consecutive_5xx: 5,
+            consecutive_connect_failures: 5,
+            base_ejection_time: Duration::from_secs(base),
+            max_ejection_time: Duration::from_secs(max),
+            max_ejection_percent: 50,
+        }
+    }
+
+    #[test]
+    fn ejection_doubles_up_to_max_ejection_time() {
+        let state = BackendState::default();
+        let params = outlier(10, 30);
+        assert!(!state.is_ejected());
+        assert_eq!(state.eject(&params), Duration::from_secs(10));
+        assert!(state.is_ejected());
+        assert_eq!(state.eject(&params), Duration::from_secs(20));
+        assert_eq!(state.eject(&params), Duration::from_secs(30));
+        assert_eq!(state.eject(&params), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn is_ejected_only_reads_the_state() {
+        let state = BackendState::default();
+        state.eject(&outlier(0, 300));
+        // An expired ejection is no longer in effect, but only a success clears the flag
+        assert!(!state.is_ejected());
+        assert!(state.ejected.load(Ordering::Relaxed));
+        state.record_success(&outlier(0, 300));
+        assert!(!state.ejected.load(Ordering::Relaxed));
+        assert_eq!(state.ejections.load(Ordering::Relaxed), 1);
+        state.eject(&outlier(0, 0));
+        state.ejected_until.store(0, Ordering::Relaxed);
+        state.record_success(&outlier(0, 0));
+        assert_eq!(state.ejections.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn record_success_keeps_a_running_ejection() {
+        let state = BackendState::default();
+        state.eject(&outlier(60, 300));
+        state.record_success(&outlier(60, 300));
+        assert!(state.is_ejected());
+    }
+}
@@ -1,10 +1,11 @@
 use crate::tls::load;
 use crate::tls::load::CertificateConfig;
-use crate::utils::structs::{Extraparams, InnerMapForJson, UpstreamSnapshotForJson, UpstreamsDashMap, UpstreamsIdMap};
+use crate::utils::structs::{Extraparams, HeaderRule, HostPattern, InnerMap, InnerMapForJson, RouteKey, UpstreamSnapshotForJson, UpstreamsDashMap, UpstreamsIdMap};
 use dashmap::DashMap;
 use log::{error, info};
 use notify::{event::ModifyKind, Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use privdrop::PrivDrop;
+use regex::Regex;
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use std::any::type_name;
@@ -23,6 +24,30 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::{fs, process, thread, time};
 
+// Compiles wildcard "*.example.com" and regex "~..." upstream hosts. Wildcards go first, the longest suffix wins, then regexes.
+pub fn host_patterns(upstreams: &UpstreamsDashMap) -> Vec<HostPattern> {
+    let mut wildcards = Vec::new();
+    let mut regexes = Vec::new();
+    for entry in upstreams.iter() {
+        let key = entry.key();
+        let (pattern, list) = if let Some(suffix) = key.strip_prefix("*.") {
+            (format!("(?i)^([^.]+)\\.{}$", regex::escape(suffix)), &mut wildcards)
+        } else if let Some(pattern) = key.strip_prefix('~') {
+            (pattern.trim().to_string(), &mut regexes)
+        } else {
+            continue;
+        };
+        match Regex::new(&pattern) {
+            Ok(regex) => list.push(HostPattern { regex, key: key.clone() }),
+            Err(e) => error!("Invalid host pattern {}: {}", key, e),
+        }
+    }
+    wildcards.sort_by(|a: &HostPattern, b: &HostPattern| b.key.len().cmp(&a.key.len()).then_with(|| a.key.cmp(&b.key)));
+    regexes.sort_by(|a: &HostPattern, b: &HostPattern| a.key.cmp(&b.key));
+    wildcards.extend(regexes);
+    wildcards
+}
+
 pub fn print_upstreams(upstreams: &UpstreamsDashMap, extraparams: &Extraparams) {
     let mut out = String::new();
     for host_entry in upstreams.iter() {
@@ -32,9 +57,10 @@ pub fn print_upstreams(upstreams: &UpstreamsDashMap, extraparams: &Extraparams)
             for f in path_entry.value().0.clone() {
                 writeln!(
                     out,
-                    "        IP: {}, Port: {}, SSL: {}, H2: {}, To HTTPS: {}, Rate Limit: {}, 4xx Limit: {}",
+                    "        IP: {}, Port: {}, Weight: {}, SSL: {}, H2: {}, To HTTPS: {}, Rate Limit: {}, 4xx Limit: {}",
                     f.address,
                     f.port,
+                    f.weight,
                     f.is_ssl,
                     f.is_http2,
                     f.to_https,
@@ -103,6 +129,47 @@ pub fn clone_dashmap_into(original: &UpstreamsDashMap, cloned: &UpstreamsDashMap
     }
 }
 
+// Reuses runtime state (health, ejections, detected protocol) of backends which stay configured after a reload,
+// so reloads do not bring dead backends back or reset their counters
+pub fn carry_state(new: &UpstreamsDashMap, old: &UpstreamsDashMap) {
+    for host in new.iter() {
+        let Some(old_host) = old.get(host.key()) else {
+            continue;
+        };
+        for mut path in host.value().iter_mut() {
+            let Some(old_path) = old_host.get(path.key()) else {
+                continue;
+            };
+            for backend in path.value_mut().0.iter_mut() {
+                if let Some(prev) = old_path.0.iter().find(|b| b.address == backend.address && b.port == backend.port) {
+                    let (is_ssl, is_http2) = backend.protocol.flags().or_else(|| prev.state.tls_detected()).unwrap_or((backend.is_ssl, backend.is_http2));
+                    *backend = Arc::new(InnerMap {
+                        is_ssl,
+                        is_http2,
+                        state: prev.state.clone(),
+                        ..(**backend).clone()
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Same as clone_dashmap_into, but skips backends marked down by health checks
+pub fn clone_live_into(original: &UpstreamsDashMap, cloned: &UpstreamsDashMap) {
+    cloned.clear();
+    for outer_entry in original.iter() {
+        let new_inner_map = DashMap::new();
+        for inner_entry in outer_entry.value().iter() {
+            let live = inner_entry.value().0.iter().filter(|b| !b.state.down.load(Ordering::Relaxed)).cloned().collect();
+            new_inner_map.insert(inner_entry.key().clone(), (live, AtomicUsize::new(0)));
+        }
+        cloned.insert(outer_entry.key().clone(), new_inner_map);
+    }
+}
+
+// BackendState is left out of Hash and Eq of InnerMap, so its atomics never change a key
+#[allow(clippy::mutable_key_type)]
 pub fn compare_dashmaps(map1: &UpstreamsDashMap, map2: &UpstreamsDashMap) -> bool {
     if map1.len() != map2.len() {
         return false;
@@ -134,7 +201,7 @@ pub fn compare_dashmaps(map1: &UpstreamsDashMap, map2: &UpstreamsDashMap) -> boo
     true
 }
 
-pub fn merge_headers(target: &DashMap<Arc<str>, Vec<(String, Arc<str>)>>, source: &DashMap<Arc<str>, Vec<(String, Arc<str>)>>) {
+pub fn merge_headers(target: &DashMap<RouteKey, Vec<HeaderRule>>, source: &DashMap<RouteKey, Vec<HeaderRule>>) {
     for entry in source.iter() {
         let global_key = entry.key().clone();
         let global_values = entry.value().clone();
@@ -220,13 +287,11 @@ pub fn watch_folder(path: String, sender: Sender<Vec<CertificateConfig>>) -> not
     loop {
         match rx.recv_timeout(Duration::from_secs(1)) {
             Ok(Ok(event)) => match &event.kind {
-                EventKind::Modify(ModifyKind::Data(_)) | EventKind::Create(_) | EventKind::Remove(_) => {
-                    if start.elapsed() > Duration::from_secs(1) {
-                        start = Instant::now();
-                        let certificate_configs = listdir(path.clone());
-                        sender.send(certificate_configs)?;
-                        info!("Certificate changed: {:?}, {:?}", event.kind, event.paths);
-                    }
+                EventKind::Modify(ModifyKind::Data(_)) | EventKind::Create(_) | EventKind::Remove(_) if start.elapsed() > Duration::from_secs(1) => {
+                    start = Instant::now();
+                    let certificate_configs = listdir(path.clone());
+                    sender.send(certificate_configs)?;
+                    info!("Certificate changed: {:?}, {:?}", event.kind, event.paths);
                 }
                 _ => {}
             },
@@ -288,6 +353,9 @@ pub fn upstreams_to_json(upstreams: &UpstreamsDashMap) -> serde_json::Result<Str
                         .map(|a| InnerMapForJson {
                             address: a.address.to_string(),
                             port: a.port,
+                            weight: a.weight,
+                            in_flight: a.state.in_flight.load(Ordering::Relaxed),
+                            ewma_latency_us: a.state.ewma_latency.load(Ordering::Relaxed),
                             is_ssl: a.is_ssl,
                             is_http2: a.is_http2,
                             to_https: a.to_https,
@@ -315,7 +383,7 @@ pub fn upstreams_liveness_json(configured: &UpstreamsDashMap, current: &Upstream
         let hostname = host_entry.key().to_string();
         let configured_paths = host_entry.value();
 
-        let mut paths_json = serde_json::Map::new();
+        let mut paths_json = json!({});
 
         for path_entry in configured_paths.iter() {
             let path = path_entry.key().clone();
@@ -324,7 +392,7 @@ pub fn upstreams_liveness_json(configured: &UpstreamsDashMap, current: &Upstream
                 .iter()
                 .map(|backend| {
                     let alive = if let Some(host_map) = current.get(&*hostname) {
-                        if let Some(path_entry) = host_map.get(&*path) {
+                        if let Some(path_entry) = host_map.get(&path) {
                             let list = &path_entry.value().0; // Vec<Arc<InnerMap>>
                             list.iter().any(|b| b.address == backend.address && b.port == backend.port)
                         } else {
@@ -336,20 +404,28 @@ pub fn upstreams_liveness_json(configured: &UpstreamsDashMap, current: &Upstream
                     json!({
                         "address": &*backend.address,
                         "port": backend.port,
-                        "alive": alive
+                        "weight": backend.weight,
+                        "alive": alive,
+                        "consecutive_successes": backend.state.hc_successes.load(Ordering::Relaxed),
+                        "consecutive_failures": backend.state.hc_failures.load(Ordering::Relaxed),
+                        "last_change": backend.state.last_change.load(Ordering::Relaxed),
+                        "ejected": backend.state.is_ejected()
                     })
                 })
                 .collect();
 
-            paths_json.insert(
-                path.to_string(),
-                json!({
-                    "backends": backends_json
-                }),
-            );
+            // Pools of match rules and traffic groups are nested under their path
+            let mut slot = &mut paths_json[&*path.path];
+            if let Some(n) = path.rule {
+                slot = &mut slot["rules"][n.to_string()];
+            }
+            if let Some(group) = &path.group {
+                slot = &mut slot["groups"][&**group];
+            }
+            slot["backends"] = Value::Array(backends_json);
         }
 
-        result.insert(hostname, Value::Object(paths_json));
+        result.insert(hostname, paths_json);
     }
     Value::Object(result)
 }
@@ -377,3 +453,34 @@ pub fn write_pid_file(path: &str) -> std::io::Result<()> {
     file.write_all(process::id().to_string().as_bytes())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(names: &[&str]) -> UpstreamsDashMap {
+        let upstreams = UpstreamsDashMap::new();
+        for name in names {
+            upstreams.insert(Arc::from(*name), DashMap::new());
+        }
+        upstreams
+    }
+
+    fn matching<'a>(patterns: &'a [HostPattern], host: &str) -> Option<&'a str> {
+        patterns.iter().find(|p| p.regex.is_match(host)).map(|p| &*p.key)
+    }
+
+    #[test]
+    fn host_patterns_order_and_match() {
+        let patterns = host_patterns(&hosts(&["example.com", "*.example.com", "*.api.example.com", "~^(.+)-eu\\.example\\.net$", "~(broken"]));
+        let keys: Vec<&str> = patterns.iter().map(|p| &*p.key).collect();
+        assert_eq!(keys, ["*.api.example.com", "*.example.com", "~^(.+)-eu\\.example\\.net$"]);
+        assert_eq!(matching(&patterns, "v1.api.example.com"), Some("*.api.example.com"));
+        assert_eq!(matching(&patterns, "WWW.Example.com"), Some("*.example.com"));
+        assert_eq!(matching(&patterns, "example.com"), None);
+        assert_eq!(matching(&patterns, "a.b.example.com"), None);
+        assert_eq!(matching(&patterns, "shop-eu.example.net"), Some("~^(.+)-eu\\.example\\.net$"));
+        let caps = patterns[2].regex.captures("shop-eu.example.net").unwrap();
+        assert_eq!(&caps[1], "shop");
+    }
+}
@@ -1,7 +1,13 @@
 pub mod acme;
 pub mod bgservice;
+pub mod cache;
+pub mod compression;
+pub mod forwarding;
 pub mod gethosts;
 pub mod logging;
+pub mod mirror;
 pub mod proxyhttp;
+pub mod proxyprotocol;
+pub mod requestid;
 pub mod start;
 pub mod webserver;
@@ -73,8 +73,9 @@ impl BackgroundService for LB {
         let ff = self.ump_full.clone();
         let im = self.ump_byid.clone();
         let (hc_method, hc_interval) = (self.config.hc_method.clone(), self.config.hc_interval);
+        let (hc_timeout, hc_concurrency) = (self.config.hc_timeout, self.config.hc_concurrency);
         drop(tokio::spawn(async move {
-            healthcheck::hc2(uu, ff, im, (&*hc_method.to_string(), hc_interval.to_string().parse().unwrap())).await
+            healthcheck::hc2(uu, ff, im, (&*hc_method.to_string(), hc_interval.to_string().parse().unwrap(), hc_timeout, hc_concurrency)).await
         }));
         drop(tokio::spawn(async move { refresh_order(certdir, confdir).await }));
         init_logging(self.config.access_log.clone());
@@ -85,9 +86,11 @@ impl BackgroundService for LB {
                 }
                 val = rx.recv() => {
                     if let Some(ss) = val {
+                        carry_state(&ss.upstreams, &self.ump_full);
                         clone_dashmap_into(&ss.upstreams, &self.ump_full);
-                        clone_dashmap_into(&ss.upstreams, &self.ump_upst);
+                        clone_live_into(&ss.upstreams, &self.ump_upst);
                         clone_idmap_into(&ss.upstreams, &self.ump_byid);
+                        lazylock::HASH_RINGS.clear();
                         let current = self.extraparams.load_full();
                         let mut new = (*current).clone();
                         new.to_https = ss.extraparams.to_https;
@@ -95,6 +98,7 @@ impl BackgroundService for LB {
                         new.authentication = ss.extraparams.authentication.clone();
                         new.rate_limit = ss.extraparams.rate_limit;
                         new.x4xx_limit = ss.extraparams.x4xx_limit;
+                        new.host_patterns = Arc::new(host_patterns(&ss.upstreams));
                         self.extraparams.store(Arc::new(new));
                         self.client_headers.clear();
                         self.server_headers.clear();
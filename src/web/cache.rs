@@ -0,0 +1,540 @@
+use crate::utils::metrics::CACHE_REQUESTS;
+use crate::utils::structs::CacheParams;
+use axum::body::Bytes;
+use axum::http::HeaderValue;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::info;
+use moka::policy::EvictionPolicy;
+use moka::sync::Cache;
+use moka::Expiry;
+use pingora::http::{Method, RequestHeader, ResponseHeader};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+static CACHE: OnceLock<Cache<Arc<str>, Arc<CachedObject>>> = OnceLock::new();
+// Vary header names of the last response stored for a host and URI, its variants are keyed by the request values of them
+static VARY: LazyLock<Cache<Arc<str>, Arc<[String]>>> = LazyLock::new(|| Cache::builder().max_capacity(VARY_ENTRIES).build());
+// Misses being fetched right now, other requests for the same key wait until the sender is dropped
+static LOCKS: LazyLock<DashMap<Arc<str>, watch::Receiver<()>>> = LazyLock::new(DashMap::new);
+const DEFAULT_SIZE: u64 = 256 * 1024 * 1024;
+const VARY_ENTRIES: u64 = 65536;
+const CACHEABLE_STATUS: [u16; 7] = [200, 203, 300, 301, 308, 404, 410];
+const HOP_HEADERS: [&str; 6] = ["connection", "keep-alive", "transfer-encoding", "set-cookie", "x-cache", "age"];
+
+pub fn init_cache(size: Option<u64>) {
+    let size = size.unwrap_or(DEFAULT_SIZE);
+    if CACHE.set(build_store(size)).is_ok() {
+        info!("Response cache size: {} bytes", size);
+    }
+}
+
+pub fn store() -> &'static Cache<Arc<str>, Arc<CachedObject>> {
+    CACHE.get_or_init(|| build_store(DEFAULT_SIZE))
+}
+
+fn build_store(size: u64) -> Cache<Arc<str>, Arc<CachedObject>> {
+    Cache::builder()
+        .max_capacity(size)
+        .weigher(|_, object: &Arc<CachedObject>| object.weight)
+        .eviction_policy(EvictionPolicy::lru())
+        .expire_after(CacheExpiry)
+        .build()
+}
+
+// Entries leave the store once they can't be served even as stale
+struct CacheExpiry;
+impl Expiry<Arc<str>, Arc<CachedObject>> for CacheExpiry {
+    fn expire_after_create(&self, _key: &Arc<str>, value: &Arc<CachedObject>, current_time: Instant) -> Option<Duration> {
+        Some(value.stale_until.saturating_duration_since(current_time))
+    }
+}
+
+pub struct CachedObject {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+    vary: Vec<(String, Option<String>)>,
+    stored: Instant,
+    fresh_until: Instant,
+    stale_until: Instant,
+    revalidating: AtomicBool,
+    weight: u32,
+}
+
+impl CachedObject {
+    pub fn age(&self) -> u64 {
+        self.stored.elapsed().as_secs()
+    }
+
+    fn vary_matches(&self, req: &RequestHeader) -> bool {
+        self.vary.iter().all(|(name, value)| header_value(req, name) == value.as_deref())
+    }
+}
+
+// Host and URI of GET and HEAD requests, None when the request must not be served from the cache
+pub fn cache_key(host: &str, req: &RequestHeader) -> Option<Arc<str>> {
+    if req.method != Method::GET && req.method != Method::HEAD {
+        return None;
+    }
+    if req.headers.contains_key("authorization") || directives(req.headers.get_all("cache-control").iter()).any(|(d, _)| d == "no-store") {
+        return None;
+    }
+    let uri = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+    Some(Arc::from(format!("{}{}", host.to_ascii_lowercase(), uri)))
+}
+
+pub enum CacheOutcome {
+    // HIT or STALE object to send without asking the upstream
+    Serve(Arc<CachedObject>, &'static str),
+    // MISS or EXPIRED, the request goes to the upstream and may fill the cache
+    Fetch(Box<CacheRequest>),
+}
+
+pub async fn lookup(params: &Arc<CacheParams>, base: Arc<str>, req: &RequestHeader) -> CacheOutcome {
+    let key = match VARY.get(&base) {
+        Some(names) => variant_key(&base, names.iter().map(|name| (name.as_str(), header_value(req, name)))),
+        None => base.clone(),
+    };
+    if let Some(outcome) = cached(params, &base, &key, req) {
+        return outcome;
+    }
+    // HEAD responses have no body to store, they neither lead nor wait for a fetch
+    if req.method == Method::HEAD {
+        return CacheOutcome::Fetch(Box::new(CacheRequest::new(params, base, key, "MISS", None, None)));
+    }
+    let mut waiting = match LOCKS.entry(key.clone()) {
+        Entry::Occupied(entry) => entry.get().clone(),
+        Entry::Vacant(entry) => {
+            let (tx, rx) = watch::channel(());
+            entry.insert(rx.clone());
+            return CacheOutcome::Fetch(Box::new(CacheRequest::new(params, base, key, "MISS", Some((tx, rx)), None)));
+        }
+    };
+    let _ = tokio::time::timeout(params.lock_timeout, waiting.changed()).await;
+    cached(params, &base, &key, req).unwrap_or_else(|| CacheOutcome::Fetch(Box::new(CacheRequest::new(params, base, key, "MISS", None, None))))
+}
+
+// Key of a variant, the host and URI followed by the request values of the Vary headers.
+// Header values can't contain a newline, so variants never clash with each other or with plain URIs.
+fn variant_key<'a>(base: &str, values: impl Iterator<Item = (&'a str, Option<&'a str>)>) -> Arc<str> {
+    let mut key = base.to_string();
+    for (name, value) in values {
+        key.push('\n');
+        key.push_str(name);
+        if let Some(value) = value {
+            key.push(':');
+            key.push_str(value);
+        }
+    }
+    Arc::from(key)
+}
+
+// Stale objects are served while one request refreshes them
+fn cached(params: &Arc<CacheParams>, base: &Arc<str>, key: &Arc<str>, req: &RequestHeader) -> Option<CacheOutcome> {
+    let object = store().get(key).filter(|o| o.vary_matches(req))?;
+    let now = Instant::now();
+    if now >= object.stale_until {
+        return None;
+    }
+    if now < object.fresh_until || object.revalidating.swap(true, Ordering::AcqRel) {
+        let status = if now < object.fresh_until { "HIT" } else { "STALE" };
+        CACHE_REQUESTS.with_label_values(&[status]).inc();
+        return Some(CacheOutcome::Serve(object, status));
+    }
+    Some(CacheOutcome::Fetch(Box::new(CacheRequest::new(
+        params,
+        base.clone(),
+        key.clone(),
+        "EXPIRED",
+        None,
+        Some(object),
+    ))))
+}
+
+// Invalidates objects of the host with URIs starting with the prefix, all variants included, returns how many were removed
+pub fn purge(host: &str, prefix: &str) -> usize {
+    let host = host.to_ascii_lowercase();
+    let keys: Vec<Arc<str>> = store().iter().filter(|(key, _)| purge_matches(key, &host, prefix)).map(|(key, _)| (*key).clone()).collect();
+    for key in keys.iter() {
+        store().invalidate(key);
+    }
+    for (key, _) in VARY.iter().filter(|(key, _)| purge_matches(key, &host, prefix)) {
+        VARY.invalidate(&*key);
+    }
+    keys.len()
+}
+
+fn purge_matches(key: &str, host: &str, prefix: &str) -> bool {
+    let uri = key.split('\n').next().unwrap_or_default();
+    uri.strip_prefix(host).is_some_and(|uri| uri.starts_with('/') && uri.starts_with(prefix))
+}
+
+struct CacheFill {
+    header: ResponseHeader,
+    body: Vec<u8>,
+    vary: Vec<(String, Option<String>)>,
+    fresh: Duration,
+    stale: Duration,
+}
+
+// Cache state of a request going to the upstream, key is the variant the request asked for
+pub struct CacheRequest {
+    params: Arc<CacheParams>,
+    base: Arc<str>,
+    key: Arc<str>,
+    pub status: &'static str,
+    lock: Option<(watch::Sender<()>, watch::Receiver<()>)>,
+    revalidating: Option<Arc<CachedObject>>,
+    fill: Option<CacheFill>,
+}
+
+impl CacheRequest {
+    fn new(
+        params: &Arc<CacheParams>,
+        base: Arc<str>,
+        key: Arc<str>,
+        status: &'static str,
+        lock: Option<(watch::Sender<()>, watch::Receiver<()>)>,
+        revalidating: Option<Arc<CachedObject>>,
+    ) -> Self {
+        CACHE_REQUESTS.with_label_values(&[status]).inc();
+        Self {
+            params: params.clone(),
+            base,
+            key,
+            status,
+            lock,
+            revalidating,
+            fill: None,
+        }
+    }
+
+    // Decides if the upstream response can be stored, called before any client headers are added to it
+    pub fn response_header(&mut self, req: &RequestHeader, resp: &ResponseHeader) {
+        self.fill = self.storable(req, resp);
+        if self.fill.is_none() {
+            self.release();
+        }
+    }
+
+    pub fn response_body(&mut self, body: &Option<Bytes>, end_of_stream: bool) {
+        let Some(fill) = self.fill.as_mut() else {
+            return;
+        };
+        if let Some(chunk) = body {
+            if fill.body.len() + chunk.len() > self.params.max_body {
+                self.fill = None;
+                self.release();
+                return;
+            }
+            fill.body.extend_from_slice(chunk);
+        }
+        if end_of_stream {
+            if let Some(fill) = self.fill.take() {
+                // The response decides which headers its variants differ by, for the next lookups of the URI
+                let key = if fill.vary.is_empty() {
+                    VARY.invalidate(&self.base);
+                    self.base.clone()
+                } else {
+                    VARY.insert(self.base.clone(), fill.vary.iter().map(|(name, _)| name.clone()).collect());
+                    variant_key(&self.base, fill.vary.iter().map(|(name, value)| (name.as_str(), value.as_deref())))
+                };
+                store().insert(key, Arc::new(fill.into_object()));
+            }
+            self.release();
+        }
+    }
+
+    fn storable(&self, req: &RequestHeader, resp: &ResponseHeader) -> Option<CacheFill> {
+        if req.method != Method::GET || !CACHEABLE_STATUS.contains(&resp.status.as_u16()) || resp.headers.contains_key("set-cookie") {
+            return None;
+        }
+        let mut max_age = None;
+        let mut stale = self.params.stale_while_revalidate;
+        for (directive, value) in directives(resp.headers.get_all("cache-control").iter()) {
+            let seconds = value.and_then(|v| v.trim_matches('"').parse::<u64>().ok()).map(Duration::from_secs);
+            match directive.as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                "s-maxage" => max_age = seconds.or(max_age),
+                "max-age" if max_age.is_none() => max_age = seconds,
+                "stale-while-revalidate" => stale = stale.max(seconds.unwrap_or_default()),
+                _ => {}
+            }
+        }
+        let fresh = if self.params.ttl_override {
+            self.params.ttl?
+        } else {
+            max_age.or_else(|| expires(resp)).or(self.params.ttl)?
+        };
+        if fresh.is_zero() {
+            return None;
+        }
+        let length = header_value_resp(resp, "content-length").and_then(|v| v.parse::<usize>().ok());
+        if length.is_some_and(|l| l > self.params.max_body) {
+            return None;
+        }
+        let mut vary = Vec::new();
+        for value in resp.headers.get_all("vary").iter().filter_map(|v| v.to_str().ok()) {
+            for name in value.split(',').map(|n| n.trim().to_ascii_lowercase()).filter(|n| !n.is_empty()) {
+                if name == "*" {
+                    return None;
+                }
+                let value = header_value(req, &name).map(str::to_string);
+                vary.push((name, value));
+            }
+        }
+        let mut header = resp.clone();
+        for name in HOP_HEADERS {
+            header.remove_header(name);
+        }
+        Some(CacheFill {
+            header,
+            body: Vec::with_capacity(length.unwrap_or(0)),
+            vary,
+            fresh,
+            stale,
+        })
+    }
+
+    // Wakes up requests waiting for this fetch and allows another refresh of a stale object
+    fn release(&mut self) {
+        if let Some((_, mine)) = self.lock.take() {
+            LOCKS.remove_if(&self.key, |_, rx| rx.same_channel(&mine));
+        }
+        if let Some(object) = self.revalidating.take() {
+            object.revalidating.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for CacheRequest {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl CacheFill {
+    fn into_object(self) -> CachedObject {
+        let mut header = self.header;
+        let _ = header.insert_header("Content-Length", self.body.len().to_string());
+        let headers_size: usize = header.headers.iter().map(|(k, v)| k.as_str().len() + v.len()).sum();
+        let now = Instant::now();
+        CachedObject {
+            weight: u32::try_from(self.body.len() + headers_size).unwrap_or(u32::MAX),
+            header,
+            body: Bytes::from(self.body),
+            vary: self.vary,
+            stored: now,
+            fresh_until: now + self.fresh,
+            stale_until: now + self.fresh + self.stale,
+            revalidating: AtomicBool::new(false),
+        }
+    }
+}
+
+// Lowercase directive names with optional values of possibly repeated Cache-Control headers
+fn directives<'a>(values: impl Iterator<Item = &'a HeaderValue> + 'a) -> impl Iterator<Item = (String, Option<String>)> + 'a {
+    values.filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).map(|d| match d.split_once('=') {
+        Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().to_string())),
+        None => (d.trim().to_ascii_lowercase(), None),
+    })
+}
+
+fn header_value<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn header_value_resp<'a>(resp: &'a ResponseHeader, name: &str) -> Option<&'a str> {
+    resp.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// Expires relative to Date of the response, invalid dates mean already expired
+fn expires(resp: &ResponseHeader) -> Option<Duration> {
+    let expires = header_value_resp(resp, "expires")?;
+    let Some(expires) = http_date(expires) else {
+        return Some(Duration::ZERO);
+    };
+    let date = header_value_resp(resp, "date")
+        .and_then(http_date)
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    Some(Duration::from_secs(expires.saturating_sub(date)))
+}
+
+// IMF-fixdate "Sun, 06 Nov 1994 08:49:37 GMT" as seconds since the epoch
+fn http_date(value: &str) -> Option<u64> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let month = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"]
+        .iter()
+        .position(|m| m == month)? as i64
+        + 1;
+    let (day, year): (i64, i64) = (day.parse().ok()?, year.parse().ok()?);
+    let mut hms = time.split(':').map(|n| n.parse::<i64>().ok());
+    let (h, m, s) = (hms.next()??, hms.next()??, hms.next()??);
+    // Days since the epoch of a proleptic Gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + h * 3600 + m * 60 + s).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(ttl: Option<u64>, ttl_override: bool) -> Arc<CacheParams> {
+        Arc::new(CacheParams {
+            ttl: ttl.map(Duration::from_secs),
+            ttl_override,
+            stale_while_revalidate: Duration::ZERO,
+            max_body: 1024,
+            lock_timeout: Duration::from_secs(1),
+        })
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, b"/path?q=1", None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(status, None).unwrap();
+        for (name, value) in headers {
+            resp.append_header(name.to_string(), *value).unwrap();
+        }
+        resp
+    }
+
+    // (fresh, stale) seconds of a storable response
+    fn storable(params: Arc<CacheParams>, req: &RequestHeader, resp: &ResponseHeader) -> Option<(u64, u64)> {
+        let cache = CacheRequest::new(&params, Arc::from("test"), Arc::from("test"), "MISS", None, None);
+        cache.storable(req, resp).map(|fill| (fill.fresh.as_secs(), fill.stale.as_secs()))
+    }
+
+    #[test]
+    fn cache_key_skips_private_requests() {
+        assert_eq!(cache_key("Example.COM", &request("GET", &[])).as_deref(), Some("example.com/path?q=1"));
+        assert_eq!(cache_key("example.com", &request("HEAD", &[])).as_deref(), Some("example.com/path?q=1"));
+        assert_eq!(cache_key("example.com", &request("POST", &[])), None);
+        assert_eq!(cache_key("example.com", &request("GET", &[("Authorization", "Basic eDp5")])), None);
+        assert_eq!(cache_key("example.com", &request("GET", &[("Cache-Control", "max-age=0, no-store")])), None);
+    }
+
+    #[test]
+    fn http_date_parses_imf_fixdate() {
+        assert_eq!(http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(http_date("Tue, 29 Feb 2000 12:00:00 GMT"), Some(951825600));
+        assert_eq!(http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(http_date("0"), None);
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        let resp = response(200, &[("Date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("Expires", "Sun, 06 Nov 1994 08:50:37 GMT")]);
+        assert_eq!(expires(&resp), Some(Duration::from_secs(60)));
+        let past = response(200, &[("Date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("Expires", "Sat, 05 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(expires(&past), Some(Duration::ZERO));
+        assert_eq!(expires(&response(200, &[("Expires", "0")])), Some(Duration::ZERO));
+        assert_eq!(expires(&response(200, &[])), None);
+    }
+
+    #[test]
+    fn storable_follows_cache_control() {
+        let get = request("GET", &[]);
+        assert_eq!(
+            storable(params(None, false), &get, &response(200, &[("Cache-Control", "public, max-age=60")])),
+            Some((60, 0))
+        );
+        assert_eq!(
+            storable(params(None, false), &get, &response(200, &[("Cache-Control", "max-age=60, s-maxage=120")])),
+            Some((120, 0))
+        );
+        assert_eq!(storable(params(None, false), &get, &response(200, &[("Cache-Control", "max-age=\"30\"")])), Some((30, 0)));
+        assert_eq!(
+            storable(
+                params(None, false),
+                &get,
+                &response(200, &[("Cache-Control", "max-age=60"), ("Cache-Control", "stale-while-revalidate=10")])
+            ),
+            Some((60, 10))
+        );
+        for directive in ["no-store", "no-cache", "private", "max-age=60, Private", "max-age=0"] {
+            assert_eq!(
+                storable(params(Some(60), false), &get, &response(200, &[("Cache-Control", directive)])),
+                None,
+                "{}",
+                directive
+            );
+        }
+    }
+
+    #[test]
+    fn storable_ttl_and_exclusions() {
+        let get = request("GET", &[]);
+        assert_eq!(storable(params(None, false), &get, &response(200, &[])), None);
+        assert_eq!(storable(params(Some(30), false), &get, &response(200, &[])), Some((30, 0)));
+        assert_eq!(storable(params(Some(30), true), &get, &response(200, &[("Cache-Control", "max-age=600")])), Some((30, 0)));
+        assert_eq!(storable(params(Some(30), false), &get, &response(500, &[])), None);
+        assert_eq!(storable(params(Some(30), false), &get, &response(200, &[("Set-Cookie", "a=b")])), None);
+        assert_eq!(storable(params(Some(30), false), &get, &response(200, &[("Content-Length", "2048")])), None);
+        assert_eq!(storable(params(Some(30), false), &get, &response(200, &[("Vary", "*")])), None);
+        assert_eq!(storable(params(Some(30), false), &request("HEAD", &[]), &response(200, &[])), None);
+    }
+
+    #[test]
+    fn variants_are_keyed_by_vary_values() {
+        let gzip = variant_key("host/a", [("accept-encoding", Some("gzip"))].into_iter());
+        let br = variant_key("host/a", [("accept-encoding", Some("br"))].into_iter());
+        let none = variant_key("host/a", [("accept-encoding", None)].into_iter());
+        assert_ne!(gzip, br);
+        assert_ne!(gzip, none);
+        assert_ne!(none, variant_key("host/a", [("accept-encoding", Some(""))].into_iter()));
+        assert!(purge_matches(&gzip, "host", "/a"));
+    }
+
+    #[test]
+    fn purge_matches_host_and_prefix() {
+        assert!(purge_matches("example.com/images/a.png", "example.com", "/images"));
+        assert!(purge_matches("example.com/images/a.png\naccept-encoding:gzip", "example.com", "/images/a.png"));
+        assert!(purge_matches("example.com/", "example.com", "/"));
+        assert!(!purge_matches("example.com/img", "example.com", "/images"));
+        assert!(!purge_matches("example.com.evil/images", "example.com", "/images"));
+        assert!(!purge_matches("www.example.com/images", "example.com", "/images"));
+        assert!(!purge_matches("example.com/a\naccept-encoding:/images", "example.com", "/images"));
+    }
+
+    #[test]
+    fn purge_removes_all_variants_of_the_host() {
+        let object = |vary: Vec<(String, Option<String>)>| {
+            let fill = CacheFill {
+                header: response(200, &[]),
+                body: b"body".to_vec(),
+                vary,
+                fresh: Duration::from_secs(60),
+                stale: Duration::ZERO,
+            };
+            Arc::new(fill.into_object())
+        };
+        let vary = vec![("accept-encoding".to_string(), Some("gzip".to_string()))];
+        store().insert(Arc::from("purge.test/docs/a"), object(Vec::new()));
+        store().insert(variant_key("purge.test/docs/b", [("accept-encoding", Some("gzip"))].into_iter()), object(vary));
+        store().insert(Arc::from("purge.test/other"), object(Vec::new()));
+        store().insert(Arc::from("other.test/docs/a"), object(Vec::new()));
+        assert_eq!(purge("PURGE.test", "/docs"), 2);
+        assert!(store().get("purge.test/other").is_some());
+        assert!(store().get("other.test/docs/a").is_some());
+    }
+}
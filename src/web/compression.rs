@@ -0,0 +1,214 @@
+use crate::utils::metrics::{COMPRESSED_RESPONSES, COMPRESSION_RATIO, COMPRESSION_TIME};
+use crate::utils::structs::CompressionParams;
+use axum::body::Bytes;
+use pingora::http::{Method, RequestHeader, ResponseHeader};
+use pingora_core::protocols::http::compression::{Algorithm, ResponseCompressionCtx};
+use std::sync::Arc;
+
+// Compression of a single response, negotiated from Accept-Encoding of the request
+pub struct Compressor {
+    ctx: ResponseCompressionCtx,
+    params: Arc<CompressionParams>,
+    active: bool,
+}
+
+impl Compressor {
+    pub fn new(params: &Arc<CompressionParams>, req: &RequestHeader) -> Option<Self> {
+        if req.method == Method::HEAD {
+            return None;
+        }
+        let accept = req.headers.get("accept-encoding")?.to_str().ok()?;
+        let name = negotiate(params, accept)?;
+        let (algorithm, max_level) = match name {
+            "gzip" => (Algorithm::Gzip, 9),
+            "br" => (Algorithm::Brotli, 11),
+            _ => (Algorithm::Zstd, 22),
+        };
+        // Level 0 keeps an algorithm disabled
+        let mut ctx = ResponseCompressionCtx::new(0, false, false);
+        ctx.adjust_algorithm_level(algorithm, params.level.clamp(1, max_level));
+        // pingora takes the first algorithm listed by the client, so it only gets to see the negotiated one
+        let mut req = req.clone();
+        req.insert_header("accept-encoding", name).ok()?;
+        ctx.request_filter(&req);
+        Some(Self {
+            ctx,
+            params: params.clone(),
+            active: false,
+        })
+    }
+
+    // Ranges, already encoded, small and not listed responses are passed as they are
+    pub fn response_header(&mut self, resp: &mut ResponseHeader) {
+        let status = resp.status.as_u16();
+        if !(200..300).contains(&status) || status == 204 || status == 206 {
+            return;
+        }
+        if resp.headers.contains_key("content-encoding") || resp.headers.contains_key("content-range") {
+            return;
+        }
+        let length = resp.headers.get("content-length").and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+        if length.is_some_and(|l| l < self.params.min_size) {
+            return;
+        }
+        let content_type = resp.headers.get("content-type").and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if !type_allowed(&self.params.types, content_type) {
+            return;
+        }
+        self.ctx.response_header_filter(resp, false);
+        self.active = self.ctx.is_enabled();
+        if self.active {
+            let varies = resp
+                .headers
+                .get_all("vary")
+                .iter()
+                .any(|v| v.to_str().is_ok_and(|v| v.to_ascii_lowercase().contains("accept-encoding")));
+            if !varies {
+                let _ = resp.append_header("Vary", "Accept-Encoding");
+            }
+        }
+    }
+
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if !self.active {
+            return;
+        }
+        let compressed = self.ctx.response_body_filter(body.as_ref(), end_of_stream);
+        if compressed.is_some() {
+            *body = compressed;
+        }
+    }
+
+    pub fn finish(&self) {
+        if !self.active {
+            return;
+        }
+        if let Some((algorithm, original, compressed, took)) = self.ctx.get_info() {
+            COMPRESSED_RESPONSES.with_label_values(&[algorithm]).inc();
+            COMPRESSION_TIME.observe(took.as_secs_f64());
+            if original > 0 {
+                COMPRESSION_RATIO.observe(compressed as f64 / original as f64);
+            }
+        }
+    }
+}
+
+// "text/*" covers the whole group, other entries match the media type without parameters.
+// pingora checks the type again with its own list of compressible types, so types outside of it,
+// like images and fonts, are passed as they are even when listed
+fn type_allowed(types: &[Arc<str>], content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    types.iter().any(|t| match t.strip_suffix("/*") {
+        Some(group) => mime.split_once('/').is_some_and(|(g, _)| g == group),
+        None => t.as_ref() == mime,
+    })
+}
+
+// Enabled algorithm with the highest q-value in Accept-Encoding, the one listed first wins a tie.
+// "*" stands for the enabled algorithms which are not listed, q=0 refuses an algorithm.
+fn negotiate(params: &CompressionParams, accept: &str) -> Option<&'static str> {
+    let enabled = [("gzip", params.gzip), ("br", params.brotli), ("zstd", params.zstd)];
+    let mut entries = Vec::new();
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = match parts.find_map(|p| p.trim().strip_prefix("q=")) {
+            Some(q) => match q.trim().parse::<f32>() {
+                Ok(q) => q,
+                Err(_) => continue,
+            },
+            None => 1.0,
+        };
+        entries.push((if name == "x-gzip" { "gzip".to_string() } else { name }, q));
+    }
+    let mut best: Option<(&'static str, f32)> = None;
+    for (name, q) in &entries {
+        let candidates = enabled.iter().filter(|(algorithm, on)| {
+            *on && match name.as_str() {
+                "*" => !entries.iter().any(|(listed, _)| listed == algorithm),
+                listed => listed == *algorithm,
+            }
+        });
+        for (algorithm, _) in candidates {
+            if *q > 0.0 && best.is_none_or(|(_, best_q)| *q > best_q) {
+                best = Some((*algorithm, *q));
+            }
+        }
+    }
+    best.map(|(algorithm, _)| algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(gzip: bool, brotli: bool, zstd: bool) -> Arc<CompressionParams> {
+        Arc::new(CompressionParams {
+            gzip,
+            brotli,
+            zstd,
+            min_size: 0,
+            types: Vec::new(),
+            level: 6,
+        })
+    }
+
+    fn request(method: &str, accept_encoding: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build(method, b"/", None).unwrap();
+        if let Some(value) = accept_encoding {
+            req.insert_header("Accept-Encoding", value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn content_types() {
+        let types: Vec<Arc<str>> = vec![Arc::from("text/*"), Arc::from("application/json")];
+        assert!(type_allowed(&types, "text/html; charset=utf-8"));
+        assert!(type_allowed(&types, "Text/CSS"));
+        assert!(type_allowed(&types, "application/json"));
+        assert!(!type_allowed(&types, "application/json-seq"));
+        assert!(!type_allowed(&types, "image/png"));
+        assert!(!type_allowed(&types, "text"));
+        assert!(!type_allowed(&types, ""));
+    }
+
+    #[test]
+    fn head_and_requests_without_accept_encoding_are_skipped() {
+        let all = params(true, true, true);
+        assert!(Compressor::new(&all, &request("GET", Some("gzip"))).is_some());
+        assert!(Compressor::new(&all, &request("HEAD", Some("gzip"))).is_none());
+        assert!(Compressor::new(&all, &request("GET", None)).is_none());
+    }
+
+    #[test]
+    fn highest_q_value_wins() {
+        let all = params(true, true, true);
+        assert_eq!(negotiate(&all, "gzip;q=0.5, br;q=0.9, zstd;q=0.1"), Some("br"));
+        assert_eq!(negotiate(&all, "gzip, br;q=0.9"), Some("gzip"));
+        assert_eq!(negotiate(&all, "zstd, br, gzip"), Some("zstd"));
+        assert_eq!(negotiate(&all, "GZIP ; q=1.0"), Some("gzip"));
+    }
+
+    #[test]
+    fn disabled_and_refused_algorithms_are_skipped() {
+        assert_eq!(negotiate(&params(true, false, false), "br, zstd, gzip;q=0.1"), Some("gzip"));
+        assert_eq!(negotiate(&params(true, true, false), "gzip;q=0, br;q=0.2"), Some("br"));
+        assert_eq!(negotiate(&params(true, false, false), "br, zstd"), None);
+        assert_eq!(negotiate(&params(true, true, true), "identity"), None);
+        assert_eq!(negotiate(&params(true, true, true), "gzip;q=0"), None);
+    }
+
+    #[test]
+    fn wildcard_covers_unlisted_algorithms() {
+        assert_eq!(negotiate(&params(true, true, false), "*"), Some("gzip"));
+        assert_eq!(negotiate(&params(true, true, false), "gzip;q=0, *"), Some("br"));
+        assert_eq!(negotiate(&params(true, false, false), "x-gzip;q=0.3, *;q=0.1"), Some("gzip"));
+        assert_eq!(negotiate(&params(true, true, true), "*;q=0"), None);
+    }
+
+    #[test]
+    fn invalid_q_values_are_ignored() {
+        assert_eq!(negotiate(&params(true, true, false), "br;q=high, gzip;q=0.1"), Some("gzip"));
+    }
+}
@@ -0,0 +1,196 @@
+use log::{info, warn};
+use pingora::http::RequestHeader;
+use pingora_proxy::Session;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+static TRUSTED_PROXIES: OnceLock<Vec<Cidr>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // "10.0.0.0/8", "2001:db8::/32" or a single address
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = addr.parse().ok()?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => masked(u32::from(net) as u128, 32, self.prefix) == masked(u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => masked(u128::from(net), 128, self.prefix) == masked(u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, width: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        bits >> (width - prefix)
+    }
+}
+
+pub fn init_trusted_proxies(list: &[String]) {
+    let cidrs: Vec<Cidr> = list
+        .iter()
+        .filter_map(|s| {
+            let cidr = Cidr::parse(s);
+            if cidr.is_none() {
+                warn!("Invalid trusted proxy: {}, ignoring", s);
+            }
+            cidr
+        })
+        .collect();
+    if !cidrs.is_empty() {
+        info!("Trusting forwarding headers from {} networks", cidrs.len());
+    }
+    let _ = TRUSTED_PROXIES.set(cidrs);
+}
+
+fn is_trusted(ip: &IpAddr) -> bool {
+    TRUSTED_PROXIES.get().is_some_and(|list| list.iter().any(|c| c.contains(ip)))
+}
+
+pub fn from_trusted_proxy(session: &Session) -> bool {
+    peer_ip(session).is_some_and(|ip| is_trusted(&ip))
+}
+
+fn peer_ip(session: &Session) -> Option<IpAddr> {
+    session.client_addr().and_then(|a| a.as_inet()).map(|i| i.ip())
+}
+
+// Real client address. Behind trusted proxies it is the right-most untrusted address of X-Forwarded-For,
+// otherwise the address of the connection. Used for rate limiting, hashing and logging.
+pub fn client_ip(session: &Session) -> Option<IpAddr> {
+    let peer = peer_ip(session)?;
+    let trusted = TRUSTED_PROXIES.get().map_or(&[][..], Vec::as_slice);
+    let xff = session.req_header().headers.get_all("x-forwarded-for").iter().filter_map(|v| v.to_str().ok());
+    Some(resolve_client(peer, xff, trusted))
+}
+
+fn resolve_client<'a>(peer: IpAddr, xff: impl Iterator<Item = &'a str>, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|c| c.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let chain: Vec<IpAddr> = xff.flat_map(|v| v.split(',')).filter_map(|ip| ip.trim().parse::<IpAddr>().ok()).collect();
+    chain.iter().rev().find(|ip| !is_trusted(ip)).or(chain.first()).copied().unwrap_or(peer)
+}
+
+// X-Forwarded-For/Proto/Host/Port, X-Real-IP and RFC 7239 Forwarded. Incoming values are extended
+// only when the connection comes from a trusted proxy, otherwise they are replaced.
+pub fn forwarding_headers(session: &Session, upstream_request: &mut RequestHeader) {
+    let Some(peer) = peer_ip(session) else {
+        return;
+    };
+    let trusted = is_trusted(&peer);
+    let incoming = |name: &str| {
+        if trusted {
+            session.req_header().headers.get(name).and_then(|v| v.to_str().ok())
+        } else {
+            None
+        }
+    };
+    let is_tls = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+    let proto = incoming("x-forwarded-proto").unwrap_or(if is_tls { "https" } else { "http" }).to_string();
+    let host = incoming("x-forwarded-host")
+        .or_else(|| session.req_header().uri.host())
+        .or_else(|| session.req_header().headers.get("host").and_then(|v| v.to_str().ok()))
+        .map(str::to_string);
+    let port = incoming("x-forwarded-port")
+        .map(str::to_string)
+        .or_else(|| session.server_addr().and_then(|a| a.as_inet()).map(|a| a.port().to_string()));
+
+    let mut xff = String::with_capacity(64);
+    if let Some(chain) = trusted.then(|| chain_of(session, "x-forwarded-for")).flatten() {
+        xff.push_str(&chain);
+        xff.push_str(", ");
+    }
+    write!(xff, "{}", peer).unwrap_or(());
+
+    let mut forwarded = String::with_capacity(96);
+    if let Some(chain) = trusted.then(|| chain_of(session, "forwarded")).flatten() {
+        forwarded.push_str(&chain);
+        forwarded.push_str(", ");
+    }
+    match peer {
+        IpAddr::V4(ip) => write!(forwarded, "for={}", ip).unwrap_or(()),
+        IpAddr::V6(ip) => write!(forwarded, "for=\"[{}]\"", ip).unwrap_or(()),
+    }
+    if let Some(host) = host.as_deref() {
+        write!(forwarded, ";host=\"{}\"", host).unwrap_or(());
+    }
+    write!(forwarded, ";proto={}", proto).unwrap_or(());
+
+    let real_ip = client_ip(session).unwrap_or(peer).to_string();
+    upstream_request.insert_header("X-Forwarded-For", xff).unwrap_or(());
+    upstream_request.insert_header("X-Forwarded-Proto", proto).unwrap_or(());
+    if let Some(host) = host {
+        upstream_request.insert_header("X-Forwarded-Host", host).unwrap_or(());
+    }
+    if let Some(port) = port {
+        upstream_request.insert_header("X-Forwarded-Port", port).unwrap_or(());
+    }
+    upstream_request.insert_header("X-Real-IP", real_ip).unwrap_or(());
+    upstream_request.insert_header("Forwarded", forwarded).unwrap_or(());
+}
+
+// All values of a possibly repeated header joined into one list
+fn chain_of(session: &Session, name: &str) -> Option<String> {
+    let values: Vec<&str> = session.req_header().headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(&ip("10.1.255.7")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(net.contains(&ip("::ffff:10.1.0.1")));
+        assert!(!net.contains(&ip("2001:db8::1")));
+        let single = Cidr::parse(" 192.168.1.10 ").unwrap();
+        assert!(single.contains(&ip("192.168.1.10")));
+        assert!(!single.contains(&ip("192.168.1.11")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(&ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(&ip("2001:db9::1")));
+        assert!(!v6.contains(&ip("10.0.0.1")));
+        for invalid in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/x", "example.com", ""] {
+            assert!(Cidr::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn client_ip_skips_trusted_hops_and_ignores_spoofed_entries() {
+        let trusted = [Cidr::parse("10.0.0.0/8").unwrap()];
+        // The client prepended a fake address, the trusted proxy appended the real one
+        let xff = ["6.6.6.6, 203.0.113.5", "10.0.0.2"];
+        assert_eq!(resolve_client(ip("10.0.0.1"), xff.into_iter(), &trusted), ip("203.0.113.5"));
+        // Untrusted peers are taken as is, whatever they send
+        assert_eq!(resolve_client(ip("198.51.100.1"), xff.into_iter(), &trusted), ip("198.51.100.1"));
+        assert_eq!(resolve_client(ip("10.0.0.1"), ["junk, 10.0.0.3"].into_iter(), &trusted), ip("10.0.0.3"));
+        assert_eq!(resolve_client(ip("10.0.0.1"), std::iter::empty(), &trusted), ip("10.0.0.1"));
+    }
+}
@@ -1,19 +1,32 @@
-use crate::utils::structs::InnerMap;
+use crate::utils::lazylock::{HASH_RINGS, ROUTE_TABLES};
+use crate::utils::structs::{HashOn, HeaderRule, InnerMap, LbMethod, PathSettings, RouteKey, UpstreamsDashMap};
+use crate::web::forwarding::client_ip;
 use crate::web::proxyhttp::LB;
+use dashmap::DashMap;
+use pingora::http::Method;
+use pingora_proxy::Session;
+use rand::RngExt;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct GetHostsReturHeaders {
-    pub client_headers: Option<Vec<(String, Arc<str>)>>,
-    pub server_headers: Option<Vec<(String, Arc<str>)>>,
+    pub client_headers: Option<Vec<HeaderRule>>,
+    pub server_headers: Option<Vec<HeaderRule>>,
 }
 
 pub trait GetHost {
     fn find_sticky_backend(&self, servers: &[Arc<InnerMap>], backend_id: Option<&str>) -> Option<Arc<InnerMap>>;
-    fn pick_backend(&self, servers: &[Arc<InnerMap>], index: &AtomicUsize, backend_id: Option<&str>) -> Option<Arc<InnerMap>>;
-    fn get_host(&self, peer: &str, path: &str, backend_id: Option<&str>) -> Option<Arc<InnerMap>>;
-    fn get_header(&self, peer: &str, path: &str) -> Option<GetHostsReturHeaders>;
+    fn pick_backend(&self, servers: &[Arc<InnerMap>], index: &AtomicUsize, backend_id: Option<&str>, session: &Session) -> Option<Arc<InnerMap>>;
+    fn get_host(&self, peer: &str, path: &str, backend_id: Option<&str>, session: &Session) -> Option<Arc<InnerMap>>;
+    fn get_header(&self, peer: &str, path: &str, session: &Session) -> Option<GetHostsReturHeaders>;
+    fn get_settings(&self, peer: &str, path: &str, session: &Session) -> Option<Arc<PathSettings>>;
+    fn configured_pool(&self, peer: &str, backend: &InnerMap) -> Option<Vec<Arc<InnerMap>>>;
+    fn live_pool(&self, peer: &str, backend: &InnerMap) -> Option<Vec<Arc<InnerMap>>>;
 }
 impl GetHost for LB {
     fn find_sticky_backend(&self, servers: &[Arc<InnerMap>], backend_id: Option<&str>) -> Option<Arc<InnerMap>> {
@@ -22,94 +35,106 @@ impl GetHost for LB {
         let target = bb.value();
         servers.iter().any(|s| s.address == target.address && s.port == target.port).then(|| target.clone())
     }
-    fn pick_backend(&self, servers: &[Arc<InnerMap>], index: &AtomicUsize, backend_id: Option<&str>) -> Option<Arc<InnerMap>> {
+    fn pick_backend(&self, servers: &[Arc<InnerMap>], index: &AtomicUsize, backend_id: Option<&str>, session: &Session) -> Option<Arc<InnerMap>> {
         if servers.is_empty() {
             return None;
         }
+        let not_ejected: Vec<Arc<InnerMap>>;
+        let servers: &[Arc<InnerMap>] = if servers.iter().any(|s| s.state.is_ejected()) {
+            not_ejected = servers.iter().filter(|s| !s.state.is_ejected()).cloned().collect();
+            if not_ejected.is_empty() {
+                servers
+            } else {
+                &not_ejected[..]
+            }
+        } else {
+            servers
+        };
         if let Some(target) = self.find_sticky_backend(servers, backend_id) {
             return Some(target);
         }
-        let idx = index.fetch_add(1, Ordering::Relaxed) % servers.len();
-        Some(servers[idx].clone())
-    }
-    fn get_host(&self, peer: &str, path: &str, backend_id: Option<&str>) -> Option<Arc<InnerMap>> {
-        let host_entry = self.ump_upst.get(peer)?;
-        let mut end = path.len();
-        loop {
-            let slice = &path[..end];
-            if let Some(entry) = host_entry.get(slice) {
-                let (servers, index) = entry.value();
-                if let Some(backend) = self.pick_backend(servers, index, backend_id) {
-                    return Some(backend);
+        match servers[0].lb_method {
+            LbMethod::RoundRobin => {
+                if servers.windows(2).any(|w| w[0].weight != w[1].weight) {
+                    return Some(smooth_weighted(servers));
                 }
+                let idx = index.fetch_add(1, Ordering::Relaxed) % servers.len();
+                Some(servers[idx].clone())
             }
-            if let Some(pos) = slice.rfind('/') {
-                end = pos;
-            } else {
-                break;
-            }
+            LbMethod::LeastConn => Some(least_conn(servers, index)),
+            LbMethod::P2cEwma => Some(p2c_ewma(servers)),
+            LbMethod::Random => Some(weighted_random(servers)),
+            LbMethod::RingHash => match hash_key(&servers[0].hash_on, session) {
+                Some(key) => Some(ring_for(servers).pick(key)),
+                None => {
+                    let idx = index.fetch_add(1, Ordering::Relaxed) % servers.len();
+                    Some(servers[idx].clone())
+                }
+            },
         }
-        if let Some(entry) = host_entry.get("/") {
+    }
+    fn get_host(&self, peer: &str, path: &str, backend_id: Option<&str>, session: &Session) -> Option<Arc<InnerMap>> {
+        let host_entry = self.ump_upst.get(peer)?;
+        let table = route_table(peer);
+        let pick = |key: &RouteKey| {
+            let entry = host_entry.get(key)?;
             let (servers, index) = entry.value();
-            if let Some(backend) = self.pick_backend(servers, index, backend_id) {
-                return Some(backend);
+            self.pick_backend(servers, index, backend_id, session)
+        };
+        for route in route_keys(table.as_deref(), path) {
+            let backend = match table.as_ref().map(|t| t.routes.get(route)) {
+                Some(Some(entries)) => entries.targets(session).flat_map(|target| target.pools(session)).find_map(&pick),
+                // Every configured path of a file host is in its table, discovered hosts have no table
+                Some(None) => None,
+                None => pick(&RouteKey::new(route)),
+            };
+            if backend.is_some() {
+                return backend;
             }
         }
         None
     }
 
-    fn get_header(&self, peer: &str, path: &str) -> Option<GetHostsReturHeaders> {
+    // Settings of the most specific path entry for the request, whether its upstreams are alive or not.
+    // Paths of the host which match nothing get the host settings.
+    fn get_settings(&self, peer: &str, path: &str, session: &Session) -> Option<Arc<PathSettings>> {
+        let table = route_table(peer)?;
+        let settings = route_keys(Some(&*table), path)
+            .find_map(|route| table.routes.get(route))
+            .and_then(|entries| entries.targets(session).next())
+            .map(|target| target.settings.clone())
+            .or_else(|| table.host_settings.clone());
+        settings
+    }
+
+    // All configured backends of the path serving this backend, dead or alive
+    fn configured_pool(&self, peer: &str, backend: &InnerMap) -> Option<Vec<Arc<InnerMap>>> {
+        pool_of(&self.ump_full, peer, backend)
+    }
+
+    // Backends of the same path which passed the last health check
+    fn live_pool(&self, peer: &str, backend: &InnerMap) -> Option<Vec<Arc<InnerMap>>> {
+        pool_of(&self.ump_upst, peer, backend)
+    }
+
+    fn get_header(&self, peer: &str, path: &str, session: &Session) -> Option<GetHostsReturHeaders> {
         let client_entry = self.client_headers.get(peer);
         let server_entry = self.server_headers.get(peer);
         if client_entry.is_none() && server_entry.is_none() {
             return None;
         }
-        let mut current_path = path;
-        let mut clnt_match = None;
-        if let Some(client_entry) = client_entry {
-            loop {
-                if let Some(entry) = client_entry.get(current_path) {
-                    if !entry.value().is_empty() {
-                        clnt_match = Some(entry.value().clone());
-                        break;
-                    }
-                }
-                if current_path == "/" {
-                    break;
-                }
-                if let Some(pos) = current_path.rfind('/') {
-                    current_path = if pos == 0 { "/" } else { &current_path[..pos] };
-                } else {
-                    break;
-                }
-            }
-        }
-        current_path = path;
-        let mut serv_match = None;
-        if let Some(server_entry) = server_entry {
-            loop {
-                if let Some(entry) = server_entry.get(current_path) {
-                    if !entry.value().is_empty() {
-                        serv_match = Some(entry.value().clone());
-                        break;
-                    }
-                }
-                if current_path == "/" {
-                    if let Some(entry) = server_entry.get("/") {
-                        if !entry.value().is_empty() {
-                            serv_match = Some(entry.value().clone());
-                            break;
-                        }
-                    }
-                    break;
-                }
-                if let Some(pos) = current_path.rfind('/') {
-                    current_path = if pos == 0 { "/" } else { &current_path[..pos] };
-                } else {
-                    break;
-                }
-            }
-        }
+        let table = route_table(peer);
+        let table = table.as_deref();
+        let lookup = |entry: &DashMap<RouteKey, Vec<HeaderRule>>| {
+            let rules = |key: &RouteKey| entry.get(key).filter(|e| !e.value().is_empty()).map(|e| e.value().clone());
+            // Global headers are merged under "/" even for hosts which don't configure it
+            route_keys(table, path).find_map(|route| match table.and_then(|t| t.routes.get(route)) {
+                Some(entries) => entries.targets(session).find_map(|target| rules(&target.key)),
+                None => rules(&RouteKey::new(route)),
+            })
+        };
+        let clnt_match = client_entry.and_then(|entry| lookup(entry.value()));
+        let serv_match = server_entry.and_then(|entry| lookup(entry.value()));
         let result = GetHostsReturHeaders {
             client_headers: clnt_match,
             server_headers: serv_match,
@@ -122,3 +147,509 @@ impl GetHost for LB {
         }
     }
 }
+
+fn route_table(peer: &str) -> Option<Arc<RouteTable>> {
+    ROUTE_TABLES.get(peer).map(|t| t.value().clone())
+}
+
+fn pool_of(map: &UpstreamsDashMap, peer: &str, backend: &InnerMap) -> Option<Vec<Arc<InnerMap>>> {
+    let host_entry = map.get(peer)?;
+    let pool = host_entry
+        .iter()
+        .find(|path_entry| path_entry.value().0.iter().any(|s| Arc::ptr_eq(&s.state, &backend.state)))
+        .map(|path_entry| path_entry.value().0.clone());
+    pool
+}
+
+// Nginx style smooth weighted round-robin, every pick raises all current weights by their
+// configured weight and lowers the chosen one by the total, spreading heavy backends evenly.
+fn smooth_weighted(servers: &[Arc<InnerMap>]) -> Arc<InnerMap> {
+    let mut total = 0i64;
+    let mut best = &servers[0];
+    let mut best_weight = i64::MIN;
+    for server in servers {
+        let weight = server.weight as i64;
+        total += weight;
+        let current = server.state.current_weight.fetch_add(weight, Ordering::Relaxed) + weight;
+        if current > best_weight {
+            best_weight = current;
+            best = server;
+        }
+    }
+    best.state.current_weight.fetch_sub(total, Ordering::Relaxed);
+    best.clone()
+}
+
+// Fewest in flight requests relative to weight. The scan starts at a rotating offset, so ties are spread.
+fn least_conn(servers: &[Arc<InnerMap>], index: &AtomicUsize) -> Arc<InnerMap> {
+    let start = index.fetch_add(1, Ordering::Relaxed);
+    let mut best = &servers[start % servers.len()];
+    let mut best_load = best.state.in_flight.load(Ordering::Relaxed) as u64;
+    for i in 1..servers.len() {
+        let server = &servers[(start + i) % servers.len()];
+        let load = server.state.in_flight.load(Ordering::Relaxed) as u64;
+        if load * u64::from(best.weight) < best_load * u64::from(server.weight) {
+            best = server;
+            best_load = load;
+        }
+    }
+    best.clone()
+}
+
+// Power of two choices, picks two random backends and takes the one with lower latency * load.
+fn p2c_ewma(servers: &[Arc<InnerMap>]) -> Arc<InnerMap> {
+    if servers.len() == 1 {
+        return servers[0].clone();
+    }
+    let mut rng = rand::rng();
+    let a = rng.random_range(0..servers.len());
+    let mut b = rng.random_range(0..servers.len() - 1);
+    if b >= a {
+        b += 1;
+    }
+    let cost = |s: &InnerMap| {
+        let latency = s.state.ewma_latency.load(Ordering::Relaxed).max(1) as f64;
+        let load = s.state.in_flight.load(Ordering::Relaxed) as f64 + 1.0;
+        latency * load / s.weight as f64
+    };
+    if cost(&servers[a]) <= cost(&servers[b]) {
+        servers[a].clone()
+    } else {
+        servers[b].clone()
+    }
+}
+
+fn weighted_random(servers: &[Arc<InnerMap>]) -> Arc<InnerMap> {
+    let total: u64 = servers.iter().map(|s| s.weight as u64).sum();
+    let mut point = rand::rng().random_range(0..total);
+    for server in servers {
+        if point < server.weight as u64 {
+            return server.clone();
+        }
+        point -= server.weight as u64;
+    }
+    servers[servers.len() - 1].clone()
+}
+
+// Exact and regex routes of a host and the entries of its paths. Prefix routes need no patterns, they are looked up by path segments.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    pub exact: HashMap<Arc<str>, Arc<str>>,
+    pub regex: Vec<(i32, Regex, Arc<str>)>,
+    pub routes: HashMap<Arc<str>, Route>,
+    pub host_settings: Option<Arc<PathSettings>>,
+}
+
+impl RouteTable {
+    // Path keys matching the request path, exact routes first, then regex routes by descending priority
+    pub fn matches<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a Arc<str>> + 'a {
+        self.exact
+            .get(path)
+            .into_iter()
+            .chain(self.regex.iter().filter(move |(_, re, _)| re.is_match(path)).map(|(_, _, key)| key))
+    }
+}
+
+// Entries of a configured path: the ones with match rules in config order and the one without rules
+#[derive(Debug, Default)]
+pub struct Route {
+    pub rules: Vec<(RouteRule, RouteTarget)>,
+    pub fallback: Option<RouteTarget>,
+}
+
+impl Route {
+    // The first entry whose rule matches the request, then the entry without rules
+    fn targets<'a>(&'a self, session: &Session) -> impl Iterator<Item = &'a RouteTarget> + 'a {
+        let rule = self.rules.iter().find(|(rule, _)| rule.matches(session)).map(|(_, target)| target);
+        rule.into_iter().chain(self.fallback.as_ref())
+    }
+}
+
+// Pool of a path entry, split into weighted groups if the entry has groups
+#[derive(Debug)]
+pub struct RouteTarget {
+    pub key: RouteKey,
+    pub split: Option<TrafficSplit>,
+    pub settings: Arc<PathSettings>,
+}
+
+impl RouteTarget {
+    // The picked group first, then other groups with nonzero weight as fallback. Entries without split are returned as is.
+    fn pools<'a>(&'a self, session: &Session) -> impl Iterator<Item = &'a RouteKey> + 'a {
+        let picked = self.split.as_ref().and_then(|s| s.pick(session));
+        let groups = self.split.as_ref().map_or(&[][..], |s| &s.groups[..]);
+        picked
+            .map(|n| &groups[n].1)
+            .into_iter()
+            .chain(
+                groups
+                    .iter()
+                    .enumerate()
+                    .filter(move |(n, (weight, _))| Some(*n) != picked && *weight > 0)
+                    .map(|(_, (_, key))| key),
+            )
+            .chain(self.split.is_none().then_some(&self.key))
+    }
+}
+
+// Weighted groups of a path entry as (weight, group pool). With pin_on the same client always lands in the same group while weights stay unchanged.
+#[derive(Debug, Default)]
+pub struct TrafficSplit {
+    pub groups: Vec<(u32, RouteKey)>,
+    pub pin_on: Option<HashOn>,
+}
+
+impl TrafficSplit {
+    fn pick(&self, session: &Session) -> Option<usize> {
+        self.pick_by(self.pin_on.as_ref().and_then(|pin| hash_key(pin, session)))
+    }
+
+    // Group of a pinned client hash, a random one for unpinned requests
+    fn pick_by(&self, hash: Option<u64>) -> Option<usize> {
+        let total: u64 = self.groups.iter().map(|(weight, _)| u64::from(*weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = match hash {
+            Some(hash) => hash % total,
+            None => rand::rng().random_range(0..total),
+        };
+        for (n, (weight, _)) in self.groups.iter().enumerate() {
+            if point < u64::from(*weight) {
+                return Some(n);
+            }
+            point -= u64::from(*weight);
+        }
+        None
+    }
+}
+
+// Request conditions of a routing rule, all of them must match
+#[derive(Debug, Default)]
+pub struct RouteRule {
+    pub methods: Vec<Method>,
+    pub headers: Vec<(Arc<str>, ValueMatch)>,
+    pub query: Vec<(Arc<str>, ValueMatch)>,
+}
+
+#[derive(Debug)]
+pub enum ValueMatch {
+    Present,
+    Exact(Arc<str>),
+    Regex(Regex),
+}
+
+impl ValueMatch {
+    fn matches(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (_, None) => false,
+            (ValueMatch::Present, Some(_)) => true,
+            (ValueMatch::Exact(expected), Some(v)) => v == expected.as_ref(),
+            (ValueMatch::Regex(re), Some(v)) => re.is_match(v),
+        }
+    }
+}
+
+impl RouteRule {
+    pub fn matches(&self, session: &Session) -> bool {
+        let req = session.req_header();
+        if !self.methods.is_empty() && !self.methods.contains(&req.method) {
+            return false;
+        }
+        let headers_ok = self.headers.iter().all(|(name, m)| m.matches(req.headers.get(name.as_ref()).and_then(|v| v.to_str().ok())));
+        if !headers_ok {
+            return false;
+        }
+        let query = req.uri.query().unwrap_or("");
+        self.query.iter().all(|(name, m)| {
+            let value = query.split('&').find_map(|pair| match pair.split_once('=') {
+                Some((k, v)) if k == name.as_ref() => Some(v),
+                None if pair == name.as_ref() => Some(""),
+                _ => None,
+            });
+            m.matches(value.map(|v| urlencoding::decode(v).unwrap_or(Cow::Borrowed(v))).as_deref())
+        })
+    }
+}
+
+// Route keys for the path in priority order: exact, regex, then prefixes on path segments down to "/"
+fn route_keys<'a>(table: Option<&'a RouteTable>, path: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let prefixes = std::iter::successors(Some(path), |p| match p.rfind('/') {
+        Some(0) if *p != "/" => Some("/"),
+        Some(pos) if pos > 0 => Some(&p[..pos]),
+        _ => None,
+    });
+    table
+        .into_iter()
+        .flat_map(move |t| t.matches(path).map(|key| key.as_ref()))
+        .chain(prefixes)
+        .chain((!path.starts_with('/')).then_some("/"))
+}
+
+// Ketama style hash ring, every backend owns 160 points per unit of weight, up to RING_MAX_POINTS in total.
+// Losing a backend remaps only the keys which were landing on its points.
+#[derive(Debug)]
+pub struct HashRing {
+    points: Vec<(u64, usize)>,
+    servers: Vec<Arc<InnerMap>>,
+}
+
+impl HashRing {
+    fn new(servers: &[Arc<InnerMap>]) -> Self {
+        let total: u64 = servers.iter().map(|s| u64::from(s.weight)).sum();
+        let mut points = Vec::new();
+        for (i, server) in servers.iter().enumerate() {
+            let weight = u64::from(server.weight);
+            // Big weights are scaled down to keep the ring within RING_MAX_POINTS, every server keeps at least one point
+            let replicas = if total * RING_REPLICAS > RING_MAX_POINTS {
+                (weight * RING_MAX_POINTS / total).max(1)
+            } else {
+                weight * RING_REPLICAS
+            };
+            for replica in 0..replicas {
+                let point = format!("{}:{}-{}", server.address, server.port, replica);
+                points.push((hash_bytes(point.as_bytes()), i));
+            }
+        }
+        points.sort_unstable();
+        Self {
+            points,
+            servers: servers.to_vec(),
+        }
+    }
+
+    fn pick(&self, key: u64) -> Arc<InnerMap> {
+        let pos = self.points.partition_point(|p| p.0 < key);
+        let (_, i) = self.points[pos % self.points.len()];
+        self.servers[i].clone()
+    }
+}
+
+// Rings are cached by the content of the backend list and dropped whenever live upstreams are swapped
+fn ring_for(servers: &[Arc<InnerMap>]) -> Arc<HashRing> {
+    let mut id = FNV_OFFSET;
+    for server in servers {
+        id = fnv1a(id, server.address.as_bytes());
+        id = fnv1a(id, &server.port.to_be_bytes());
+        id = fnv1a(id, &server.weight.to_be_bytes());
+    }
+    if let Some(ring) = HASH_RINGS.get(&id) {
+        return ring.value().clone();
+    }
+    let ring = Arc::new(HashRing::new(servers));
+    HASH_RINGS.insert(id, ring.clone());
+    ring
+}
+
+fn hash_key(hash_on: &HashOn, session: &Session) -> Option<u64> {
+    match hash_on {
+        HashOn::Ip => match client_ip(session)? {
+            IpAddr::V4(ip) => Some(hash_bytes(&ip.octets())),
+            IpAddr::V6(ip) => Some(hash_bytes(&ip.octets())),
+        },
+        HashOn::Uri => {
+            let uri = &session.req_header().uri;
+            Some(hash_bytes(uri.path_and_query().map_or(uri.path(), |pq| pq.as_str()).as_bytes()))
+        }
+        HashOn::Header(name) => session.req_header().headers.get(name.as_ref()).map(|v| hash_bytes(v.as_bytes())),
+        HashOn::Cookie(name) => {
+            let cookies = session.req_header().headers.get("cookie")?.to_str().ok()?;
+            cookies.split(';').find_map(|c| {
+                let (k, v) = c.trim().split_once('=')?;
+                (k == name.as_ref()).then(|| hash_bytes(v.as_bytes()))
+            })
+        }
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const RING_REPLICAS: u64 = 160;
+const RING_MAX_POINTS: u64 = 65536;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Stable across restarts and instances, unlike the randomly seeded std and ahash hashers
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut h = fnv1a(FNV_OFFSET, bytes);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(address: &str, weight: u32) -> Arc<InnerMap> {
+        Arc::new(InnerMap {
+            address: Arc::from(address),
+            weight,
+            ..InnerMap::new()
+        })
+    }
+
+    #[test]
+    fn smooth_weighted_spreads_heavy_backends() {
+        let servers = [server("a", 5), server("b", 1), server("c", 1)];
+        let picks: String = (0..14).map(|_| smooth_weighted(&servers).address.to_string()).collect();
+        assert_eq!(picks, "aabacaaaabacaa");
+    }
+
+    #[test]
+    fn smooth_weighted_follows_weights() {
+        let servers = [server("a", 3), server("b", 2), server("c", 0)];
+        let picks: Vec<Arc<InnerMap>> = (0..50).map(|_| smooth_weighted(&servers)).collect();
+        assert_eq!(picks.iter().filter(|s| &*s.address == "a").count(), 30);
+        assert_eq!(picks.iter().filter(|s| &*s.address == "b").count(), 20);
+        assert!(picks.iter().all(|s| &*s.address != "c"));
+    }
+
+    #[test]
+    fn weighted_random_stays_in_bounds() {
+        let single = [server("a", 1)];
+        assert!((0..100).all(|_| &*weighted_random(&single).address == "a"));
+        let servers = [server("a", 1), server("b", 0), server("c", u32::MAX)];
+        let picks: Vec<Arc<InnerMap>> = (0..1000).map(|_| weighted_random(&servers)).collect();
+        assert!(picks.iter().all(|s| &*s.address != "b"));
+        assert!(picks.iter().filter(|s| &*s.address == "c").count() > 990);
+    }
+
+    #[test]
+    fn least_conn_prefers_low_load_per_weight() {
+        let servers = [server("a", 1), server("b", 3), server("c", 1)];
+        servers[0].state.in_flight.store(2, Ordering::Relaxed);
+        servers[1].state.in_flight.store(3, Ordering::Relaxed);
+        servers[2].state.in_flight.store(1, Ordering::Relaxed);
+        let index = AtomicUsize::new(0);
+        // b carries 1 request per weight unit, same as c, so the rotating start decides between them
+        let picks: String = (0..4).map(|_| least_conn(&servers, &index).address.to_string()).collect();
+        assert_eq!(picks, "bbcb");
+        servers[1].state.in_flight.store(9, Ordering::Relaxed);
+        assert!((0..6).all(|_| &*least_conn(&servers, &index).address == "c"));
+    }
+
+    #[test]
+    fn p2c_ewma_picks_two_distinct_backends() {
+        let servers = [server("a", 1), server("b", 1)];
+        servers[0].state.in_flight.store(100, Ordering::Relaxed);
+        // With two backends both are always compared, so the loaded one never wins
+        assert!((0..100).all(|_| &*p2c_ewma(&servers).address == "b"));
+        assert_eq!(&*p2c_ewma(&servers[..1]).address, "a");
+    }
+
+    #[test]
+    fn hash_ring_keeps_keys_when_a_node_leaves() {
+        let servers = [server("10.0.0.1", 1), server("10.0.0.2", 1), server("10.0.0.3", 1)];
+        let full = HashRing::new(&servers);
+        let reduced = HashRing::new(&servers[..2]);
+        let keys: Vec<u64> = (0..3000u32).map(|k| hash_bytes(&k.to_be_bytes())).collect();
+        let mut moved = 0;
+        for key in &keys {
+            let before = full.pick(*key);
+            let after = reduced.pick(*key);
+            if &*before.address == "10.0.0.3" {
+                moved += 1;
+            } else {
+                assert_eq!(before.address, after.address);
+            }
+        }
+        // Only the keys of the removed node move, about a third of them
+        assert!((700..1300).contains(&moved), "{}", moved);
+    }
+
+    #[test]
+    fn hash_ring_follows_weights() {
+        let servers = [server("10.0.0.1", 3), server("10.0.0.2", 1)];
+        let ring = HashRing::new(&servers);
+        let heavy = (0..4000u32).filter(|k| &*ring.pick(hash_bytes(&k.to_be_bytes())).address == "10.0.0.1").count();
+        assert!((2600..3400).contains(&heavy), "{}", heavy);
+        assert_eq!(HashRing::new(&servers).pick(42).address, ring.pick(42).address);
+    }
+
+    #[test]
+    fn hash_ring_caps_points_for_big_weights() {
+        let servers = [server("10.0.0.1", u32::MAX), server("10.0.0.2", 1000), server("10.0.0.3", 1)];
+        let ring = HashRing::new(&servers);
+        assert!(ring.points.len() as u64 <= RING_MAX_POINTS + servers.len() as u64, "{}", ring.points.len());
+        assert!((0..servers.len()).all(|i| ring.points.iter().any(|(_, n)| *n == i)));
+        let small = HashRing::new(&[server("10.0.0.1", 2), server("10.0.0.2", 1)]);
+        assert_eq!(small.points.len() as u64, 3 * RING_REPLICAS);
+    }
+
+    #[test]
+    fn route_table_matches_exact_before_regex_by_priority() {
+        let mut table = RouteTable::default();
+        table.exact.insert(Arc::from("/api/v1"), Arc::from("= /api/v1"));
+        table.regex.push((10, Regex::new("^/api/v[0-9]+$").unwrap(), Arc::from("~ ^/api/v[0-9]+$")));
+        table.regex.push((0, Regex::new("^/api").unwrap(), Arc::from("~ ^/api")));
+        let keys = |path| table.matches(path).map(|k| k.to_string()).collect::<Vec<_>>();
+        assert_eq!(keys("/api/v1"), ["= /api/v1", "~ ^/api/v[0-9]+$", "~ ^/api"]);
+        assert_eq!(keys("/api/v2"), ["~ ^/api/v[0-9]+$", "~ ^/api"]);
+        assert_eq!(keys("/api/v1/"), ["~ ^/api"]);
+        assert!(keys("/static").is_empty());
+    }
+
+    #[test]
+    fn route_keys_go_from_exact_and_regex_to_segment_prefixes() {
+        let mut table = RouteTable::default();
+        table.exact.insert(Arc::from("/api/users"), Arc::from("= /api/users"));
+        table.regex.push((5, Regex::new("^/api/.+$").unwrap(), Arc::from("~ ^/api/.+$")));
+        table.regex.push((1, Regex::new("users").unwrap(), Arc::from("~ users")));
+        let keys: Vec<&str> = route_keys(Some(&table), "/api/users").collect();
+        assert_eq!(keys, ["= /api/users", "~ ^/api/.+$", "~ users", "/api/users", "/api", "/"]);
+        let keys: Vec<&str> = route_keys(None, "/api/v1/items/").collect();
+        assert_eq!(keys, ["/api/v1/items/", "/api/v1/items", "/api/v1", "/api", "/"]);
+        assert_eq!(route_keys(None, "/").collect::<Vec<_>>(), ["/"]);
+        assert_eq!(route_keys(None, "*").collect::<Vec<_>>(), ["*", "/"]);
+    }
+
+    #[test]
+    fn route_keys_stop_at_segment_boundaries() {
+        let keys: Vec<&str> = route_keys(None, "/apix/v1").collect();
+        assert!(!keys.contains(&"/api"), "{:?}", keys);
+        assert!(route_keys(None, "/api/v1").any(|k| k == "/api"));
+    }
+
+    #[test]
+    fn value_match_kinds() {
+        assert!(ValueMatch::Present.matches(Some("")));
+        assert!(!ValueMatch::Present.matches(None));
+        assert!(ValueMatch::Exact(Arc::from("beta")).matches(Some("beta")));
+        assert!(!ValueMatch::Exact(Arc::from("beta")).matches(Some("Beta")));
+        let re = ValueMatch::Regex(Regex::new("^v[0-9]+$").unwrap());
+        assert!(re.matches(Some("v2")));
+        assert!(!re.matches(Some("v2-rc")));
+        assert!(!re.matches(None));
+    }
+
+    #[test]
+    fn traffic_split_pins_clients_and_follows_weights() {
+        let key = RouteKey::new("/");
+        let split = TrafficSplit {
+            groups: vec![(90, key.with_group("stable")), (0, key.with_group("off")), (10, key.with_group("canary"))],
+            pin_on: Some(HashOn::Ip),
+        };
+        for hash in [0, 89, 90, 99, 12345] {
+            let first = split.pick_by(Some(hash));
+            assert_eq!(first, split.pick_by(Some(hash)));
+            assert_ne!(first, Some(1));
+        }
+        assert_eq!(split.pick_by(Some(89)), Some(0));
+        assert_eq!(split.pick_by(Some(90)), Some(2));
+        let canary = (0..10_000).filter(|_| split.pick_by(None) == Some(2)).count();
+        assert!((700..1300).contains(&canary), "{}", canary);
+        let empty = TrafficSplit {
+            groups: vec![(0, key.with_group("off"))],
+            pin_on: None,
+        };
+        assert_eq!(empty.pick_by(None), None);
+    }
+}
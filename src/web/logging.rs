@@ -1,4 +1,5 @@
 use crate::utils::metrics::LOGGING_ERRORS;
+use crate::web::forwarding::client_ip;
 use log::info;
 use pingora_http::Version;
 use pingora_proxy::Session;
@@ -13,6 +14,7 @@ pub struct LogMessage {
     pub client_ip: IpAddr,
     pub version: Version,
     pub user_agent: String,
+    pub request_id: String,
 }
 static LOG_SENDER: OnceLock<mpsc::Sender<LogMessage>> = OnceLock::new();
 static ACCESS_LOG: OnceLock<LogLevel> = OnceLock::new();
@@ -40,7 +42,7 @@ impl LogLevel {
     }
 }
 
-pub fn access_log(response_code: u16, summary: &str, session: &Session) {
+pub fn access_log(response_code: u16, summary: &str, request_id: &str, session: &Session) {
     let level = ACCESS_LOG.get().unwrap_or(&LogLevel::None);
 
     let should_log = match level {
@@ -53,11 +55,7 @@ pub fn access_log(response_code: u16, summary: &str, session: &Session) {
         return;
     }
 
-    let ip = session
-        .client_addr()
-        .and_then(|addr| addr.as_inet())
-        .map(|addr| addr.ip())
-        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let ip = client_ip(session).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
 
     let user_agent = session.req_header().headers.get("user-agent").and_then(|v| v.to_str().ok()).unwrap_or("-");
 
@@ -67,18 +65,18 @@ pub fn access_log(response_code: u16, summary: &str, session: &Session) {
         client_ip: ip,
         version: session.req_header().version,
         user_agent: user_agent.to_owned(),
+        request_id: request_id.to_owned(),
     };
 
     if let Some(sender) = LOG_SENDER.get() {
-        let sender = sender;
-        if let Err(_) = sender.try_send(log) {
+        if sender.try_send(log).is_err() {
             LOGGING_ERRORS.inc();
         }
     }
 }
 
 pub fn init_logging(enabled: Option<String>) {
-    if let Some(_) = enabled {
+    if enabled.is_some() {
         LOGGING_ERRORS.set(0);
         info!("Enabling {:?} log, with buffer of {} messages", ACCESS_LOG.get().unwrap_or(&LogLevel::None), LOG_BUFFER);
         let (ltx, lrx) = mpsc::channel(LOG_BUFFER);
@@ -90,8 +88,8 @@ pub fn init_logging(enabled: Option<String>) {
 pub fn log_receiver(mut receiver: mpsc::Receiver<LogMessage>) {
     while let Some(msg) = receiver.blocking_recv() {
         info!(
-            "{}, {}, client: {}, version: {:?}, useragent: {}",
-            msg.response_code, msg.summary, msg.client_ip, msg.version, msg.user_agent,
+            "{}, {}, client: {}, version: {:?}, useragent: {}, request_id: {}",
+            msg.response_code, msg.summary, msg.client_ip, msg.version, msg.user_agent, msg.request_id,
         );
     }
 }
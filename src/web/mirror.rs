@@ -0,0 +1,93 @@
+use crate::tls::upstream::apply_upstream_tls;
+use crate::utils::metrics::MIRROR_REQUESTS;
+use crate::utils::structs::MirrorParams;
+use axum::body::Bytes;
+use pingora::http::RequestHeader;
+use pingora_core::connectors::http::Connector;
+use pingora_core::upstreams::peer::HttpPeer;
+use rand::RngExt;
+use std::sync::{Arc, LazyLock};
+
+pub static MIRROR_CONNECTOR: LazyLock<Connector> = LazyLock::new(|| Connector::new(None));
+
+// Copy of a proxied request, the body is collected while it streams to the real upstream
+pub struct MirrorRequest {
+    params: Arc<MirrorParams>,
+    header: RequestHeader,
+    sni: String,
+    body: Vec<u8>,
+    oversized: bool,
+}
+
+impl MirrorRequest {
+    // Returns the copy only for the sampled share of requests
+    pub fn sample(params: &Arc<MirrorParams>, header: &RequestHeader, sni: &str) -> Option<Self> {
+        if params.sample < 10000 && rand::rng().random_range(0..10000) >= params.sample {
+            return None;
+        }
+        Some(Self {
+            params: params.clone(),
+            header: header.clone(),
+            sni: sni.to_string(),
+            body: Vec::new(),
+            oversized: false,
+        })
+    }
+
+    pub fn push_body(&mut self, chunk: &Bytes) {
+        if self.oversized {
+            return;
+        }
+        if self.body.len() + chunk.len() > self.params.max_body {
+            self.oversized = true;
+            self.body = Vec::new();
+            return;
+        }
+        self.body.extend_from_slice(chunk);
+    }
+
+    // Fire and forget, the client never waits for the mirror and never sees its errors.
+    // Bodies above max_body are not mirrored at all, a cut body would be an invalid request.
+    pub fn send(self) {
+        if self.oversized {
+            MIRROR_REQUESTS.with_label_values(&["skipped"]).inc();
+            return;
+        }
+        let timeout = self.params.timeout;
+        drop(tokio::spawn(async move {
+            let result = match tokio::time::timeout(timeout, self.forward()).await {
+                Ok(Ok(())) => "ok",
+                Ok(Err(e)) => {
+                    log::debug!("Mirror request failed: {}", e);
+                    "error"
+                }
+                Err(_) => "timeout",
+            };
+            MIRROR_REQUESTS.with_label_values(&[result]).inc();
+        }));
+    }
+
+    async fn forward(self) -> pingora::Result<()> {
+        let servers = &self.params.servers;
+        let (address, port, is_ssl) = &servers[rand::rng().random_range(0..servers.len())];
+        let mut peer = HttpPeer::new((&**address, *port), *is_ssl, self.sni);
+        if *is_ssl {
+            apply_upstream_tls(&mut peer, self.params.tls.as_deref());
+        }
+
+        let (mut session, _) = MIRROR_CONNECTOR.get_http_session(&peer).await?;
+        let mut header = self.header;
+        if header.remove_header("transfer-encoding").is_some() || !self.body.is_empty() {
+            header.insert_header("Content-Length", self.body.len().to_string())?;
+        }
+        session.write_request_header(Box::new(header)).await?;
+        if !self.body.is_empty() {
+            session.write_request_body(Bytes::from(self.body), true).await?;
+        }
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+        while session.read_response_body().await?.is_some() {}
+        MIRROR_CONNECTOR.release_http_session(session, &peer, None).await;
+        Ok(())
+    }
+}
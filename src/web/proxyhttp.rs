@@ -1,27 +1,41 @@
+use crate::tls::upstream::apply_upstream_tls;
 use crate::utils::auth::authenticate;
 use crate::utils::lazylock::{LOCALHOST, RATE_LIMITER, REQUESTS_4XX, REVERSE_STORE};
 use crate::utils::metrics::*;
-use crate::utils::structs::{AppConfig, Extraparams, Headers, InnerMap, UpstreamsDashMap, UpstreamsIdMap};
+use crate::utils::structs::{
+    AppConfig, ErrorPages, Extraparams, HeaderOp, HeaderRule, Headers, HostPattern, InnerMap, PathSettings, RedirectParams, RetryParams, UpstreamsDashMap, UpstreamsIdMap,
+    UriRewrite,
+};
+use crate::web::cache::{cache_key, lookup, CacheOutcome, CacheRequest, CachedObject};
+use crate::web::compression::Compressor;
+use crate::web::forwarding::{client_ip, forwarding_headers};
 use crate::web::gethosts::{GetHost, GetHostsReturHeaders};
 use crate::web::logging::access_log;
+use crate::web::mirror::MirrorRequest;
+use crate::web::proxyprotocol::ProxyV2Connect;
+use crate::web::requestid::{request_id, request_id_header};
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use axum::body::Bytes;
-use log::error;
-use pingora::http::{RequestHeader, ResponseHeader, StatusCode};
+use axum::http::Uri;
+use log::{error, warn};
+use pingora::http::{Method, RequestHeader, ResponseHeader, StatusCode};
 use pingora::prelude::*;
-use pingora::ErrorSource::Upstream;
+use pingora::ErrorSource::{Downstream, Internal, Unset, Upstream};
 use pingora_core::listeners::ALPN;
 use pingora_core::prelude::HttpPeer;
-use pingora_proxy::{ProxyHttp, Session};
+use pingora_core::protocols::l4::socket::SocketAddr;
+use pingora_proxy::{FailToProxy, ProxyHttp, Session};
+use rand::RngExt;
 use sha2::{Digest, Sha256};
-use std::cell::RefCell;
+use std::borrow::Cow;
 use std::fmt::Write;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::time::Instant;
 
-thread_local! {static IP_BUFFER: RefCell<String> = RefCell::new(String::with_capacity(50));}
-
 #[derive(Clone)]
 pub struct LB {
     pub ump_upst: Arc<UpstreamsDashMap>,
@@ -39,8 +53,47 @@ pub struct Context {
     hostname: Option<Arc<str>>,
     upstream_peer: Option<Arc<InnerMap>>,
     extraparams: arc_swap::Guard<Arc<Extraparams>>,
-    client_headers: Option<Vec<(String, Arc<str>)>>,
+    client_headers: Option<Vec<HeaderRule>>,
     x4xx_limit: Option<u32>,
+    in_flight: Option<Arc<InnerMap>>,
+    upstream_start: Option<Instant>,
+    upstream_status: Option<u16>,
+    tried: Vec<Arc<InnerMap>>,
+    matched_host: Option<Arc<str>>,
+    host_captures: Vec<String>,
+    mirror: Option<MirrorRequest>,
+    mirror_sampled: bool,
+    request_id: String,
+    compression: Option<Compressor>,
+    cache: Option<CacheRequest>,
+    cache_status: Option<&'static str>,
+    path_settings: Option<Arc<PathSettings>>,
+}
+
+// Why a request is moved to another backend, also the label of the retries metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RetryReason {
+    ConnectFailure,
+    Timeout,
+    Status5xx,
+}
+
+impl RetryReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RetryReason::ConnectFailure => "connect_failure",
+            RetryReason::Timeout => "timeout",
+            RetryReason::Status5xx => "5xx",
+        }
+    }
+
+    fn enabled(&self, params: &RetryParams) -> bool {
+        match self {
+            RetryReason::ConnectFailure => params.on_connect_failure,
+            RetryReason::Timeout => params.on_timeout,
+            RetryReason::Status5xx => params.on_5xx,
+        }
+    }
 }
 
 #[async_trait]
@@ -55,14 +108,33 @@ impl ProxyHttp for LB {
             extraparams: self.extraparams.load(),
             client_headers: None,
             x4xx_limit: None,
+            in_flight: None,
+            upstream_start: None,
+            upstream_status: None,
+            tried: Vec::new(),
+            matched_host: None,
+            host_captures: Vec::new(),
+            mirror: None,
+            mirror_sampled: false,
+            request_id: String::new(),
+            compression: None,
+            cache: None,
+            cache_status: None,
+            path_settings: None,
         }
     }
     async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool> {
         ACTIVE_SESSIONS.inc();
-        let hostname = return_header_host_from_upstream(session, &self.ump_upst);
-        _ctx.hostname = hostname;
+        _ctx.request_id = request_id(session);
+        let hostname = return_header_host_from_upstream(session, &self.ump_upst, &_ctx.extraparams.host_patterns, &mut _ctx.host_captures);
+        if let Some((key, host)) = hostname {
+            if host.as_ref() != key.as_ref() {
+                _ctx.matched_host = Some(host);
+            }
+            _ctx.hostname = Some(key);
+        }
         let mut backend_id = None;
-        if let Some(_) = _ctx.extraparams.sticky_sessions {
+        if _ctx.extraparams.sticky_sessions.is_some() {
             if let Some(cookies) = session.req_header().headers.get("cookie") {
                 if let Ok(cookie_str) = cookies.to_str() {
                     if let Some(pos) = cookie_str.find("backend_id=") {
@@ -76,60 +148,65 @@ impl ProxyHttp for LB {
         match _ctx.hostname.as_ref() {
             None => return Ok(false),
             Some(host) => {
-                let optioninnermap = self.get_host(host, session.req_header().uri.path(), backend_id);
+                _ctx.path_settings = self.get_settings(host, session.req_header().uri.path(), session);
+                // Redirects don't need an upstream, so they are answered even when all upstreams of the path are down
+                let settings = _ctx.path_settings.clone().unwrap_or_default();
+                let default_redirect = RedirectParams::default();
+                let redirect = settings.redirect.as_deref().unwrap_or(&default_redirect);
+                let uri = &session.req_header().uri;
+                let target = redirect.map_location(uri.path()).or_else(|| {
+                    settings.redirect_to.as_ref().map(|redirect_to| {
+                        let location = if redirect.replace {
+                            redirect_to.to_string()
+                        } else {
+                            format!("{}{}", redirect_to, uri.path())
+                        };
+                        (location, redirect.code)
+                    })
+                });
+                if let Some((location, code)) = target {
+                    let location = with_query(location, uri.query().filter(|_| redirect.keep_query));
+                    return send_redirect(session, _ctx, code, location).await;
+                }
+                let optioninnermap = self.get_host(host, session.req_header().uri.path(), backend_id, session);
                 match optioninnermap {
                     None => return Ok(false),
                     Some(ref innermap) => {
                         if let Some(auth) = _ctx.extraparams.authentication.as_ref().or(innermap.authorization.as_ref()) {
-                            if !authenticate(&auth, session).await {
-                                let _ = session.respond_error(401).await;
+                            if !authenticate(auth, session).await {
+                                let _ = send_error(session, _ctx, 401).await;
                                 return Ok(true);
                             }
                         }
                         if let Some(rate) = innermap.x4xx_limit.or(_ctx.extraparams.x4xx_limit) {
                             _ctx.x4xx_limit = innermap.x4xx_limit;
-                            let rate_key = session.client_addr().and_then(|addr| addr.as_inet()).map(|inet| inet.ip());
+                            let rate_key = client_ip(session);
                             if let Some(rk) = rate_key {
                                 let count = REQUESTS_4XX.get(&rk).unwrap_or(0);
                                 if count > rate {
-                                    let header = ResponseHeader::build(429, None)?;
                                     session.set_keepalive(None);
-                                    session.write_response_header(Box::new(header), true).await?;
+                                    send_error(session, _ctx, 429).await?;
                                     return Ok(true);
                                 }
                             }
                         }
                         if let Some(rate) = innermap.rate_limit.or(_ctx.extraparams.rate_limit) {
-                            let rate_key = session.client_addr().and_then(|addr| addr.as_inet()).map(|inet| inet.ip());
+                            let rate_key = client_ip(session);
                             let curr_window_requests = RATE_LIMITER.observe(&rate_key, 1);
                             if curr_window_requests > rate {
-                                let header = ResponseHeader::build(429, None)?;
                                 session.set_keepalive(None);
-                                session.write_response_header(Box::new(header), true).await?;
+                                send_error(session, _ctx, 429).await?;
                                 return Ok(true);
                             }
                         }
 
-                        if let Some(redirect_to) = &innermap.redirect_to {
-                            let uri = session.req_header().uri.path();
-                            let capacity = redirect_to.len() + uri.len();
-                            let mut s = String::with_capacity(capacity);
-                            s.push_str(redirect_to);
-                            s.push_str(uri);
-                            let mut resp = ResponseHeader::build(StatusCode::MOVED_PERMANENTLY, None)?;
-                            resp.insert_header("Location", s)?;
-                            resp.insert_header("Content-Length", "0")?;
-                            session.write_response_header(Box::new(resp), true).await?;
-                            return Ok(true);
-                        }
-
                         if _ctx.extraparams.to_https.unwrap_or(false) || innermap.to_https {
                             if let Some(stream) = session.stream() {
                                 if stream.get_ssl().is_none() {
-                                    if let Some(host) = _ctx.hostname.as_ref() {
+                                    if let Some(host) = _ctx.matched_host.as_ref().or(_ctx.hostname.as_ref()) {
                                         let port = self.config.proxy_port_tls.as_deref().unwrap_or("443");
-                                        let uri = session.req_header().uri.path();
-                                        let capacity = host.len() + uri.len() + 8;
+                                        let uri = &session.req_header().uri;
+                                        let capacity = host.len() + uri.path().len() + 8;
                                         let mut s = String::with_capacity(capacity);
                                         s.push_str("https://");
                                         s.push_str(host);
@@ -137,16 +214,29 @@ impl ProxyHttp for LB {
                                             s.push(':');
                                             s.push_str(port);
                                         }
-                                        s.push_str(uri);
-                                        let mut resp = ResponseHeader::build(StatusCode::MOVED_PERMANENTLY, None)?;
-                                        resp.insert_header("Location", s)?;
-                                        resp.insert_header("Content-Length", "0")?;
-                                        session.write_response_header(Box::new(resp), true).await?;
-                                        return Ok(true);
+                                        s.push_str(uri.path());
+                                        let s = with_query(s, uri.query().filter(|_| redirect.keep_query));
+                                        return send_redirect(session, _ctx, redirect.code, s).await;
                                     }
                                 }
                             }
                         }
+                        if let Some(params) = innermap.cache.as_ref() {
+                            let host = _ctx.matched_host.as_deref().or(_ctx.hostname.as_deref()).unwrap_or_default();
+                            match cache_key(host, session.req_header()) {
+                                Some(key) => match lookup(params, key, session.req_header()).await {
+                                    CacheOutcome::Serve(object, status) => return self.serve_cached(session, _ctx, innermap, &object, status).await,
+                                    CacheOutcome::Fetch(request) => {
+                                        _ctx.cache_status = Some(request.status);
+                                        _ctx.cache = Some(*request);
+                                    }
+                                },
+                                None => {
+                                    CACHE_REQUESTS.with_label_values(&["BYPASS"]).inc();
+                                    _ctx.cache_status = Some("BYPASS");
+                                }
+                            }
+                        }
                     }
                 }
                 _ctx.upstream_peer = optioninnermap;
@@ -158,14 +248,52 @@ impl ProxyHttp for LB {
         match ctx.hostname.as_ref() {
             Some(hostname) => match ctx.upstream_peer.as_ref() {
                 Some(innermap) => {
-                    let mut peer = Box::new(HttpPeer::new((&*innermap.address, innermap.port), innermap.is_ssl, hostname.to_string()));
+                    innermap.state.in_flight.fetch_add(1, Ordering::Relaxed);
+                    if let Some(previous) = ctx.in_flight.replace(innermap.clone()) {
+                        previous.state.in_flight.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    ctx.upstream_start = Some(Instant::now());
+                    let host_header = innermap.host_header.as_deref().map(|h| expand_captures(h, &ctx.host_captures));
+                    let sni = innermap
+                        .sni
+                        .as_deref()
+                        .or(host_header.as_deref())
+                        .or(ctx.matched_host.as_deref())
+                        .unwrap_or(hostname)
+                        .to_string();
+                    let mut peer = Box::new(HttpPeer::new((&*innermap.address, innermap.port), innermap.is_ssl, sni));
 
                     if innermap.is_http2 {
                         peer.options.alpn = ALPN::H2;
                     }
                     if innermap.is_ssl {
-                        peer.options.verify_cert = false;
-                        peer.options.verify_hostname = false;
+                        apply_upstream_tls(&mut peer, innermap.tls.as_deref());
+                    }
+                    if innermap.proxy_protocol {
+                        let inet = |addr: Option<&SocketAddr>| addr.and_then(|a| a.as_inet()).copied();
+                        // Clients without inet addresses, like on unix sockets, are sent upstream with a LOCAL header
+                        let addresses = inet(session.client_addr()).zip(inet(session.server_addr()));
+                        // The header is sent once per connection, so connections are pooled per client connection
+                        peer.options.custom_l4 = Some(Arc::new(ProxyV2Connect { addresses }));
+                        let mut hasher = DefaultHasher::new();
+                        addresses.map(|(source, _)| source).hash(&mut hasher);
+                        peer.group_key = hasher.finish();
+                    }
+                    if let Some(timeouts) = innermap.timeouts.as_deref() {
+                        // request_timeout bounds every upstream operation by the time left for the whole request
+                        let remaining = timeouts.request.map(|total| total.saturating_sub(ctx.start_time.elapsed()));
+                        if remaining.is_some_and(|r| r.is_zero()) {
+                            return Err(Error::explain(ReadTimedout, "request_timeout exceeded").into_up());
+                        }
+                        let cap = |t: Option<Duration>| match (t, remaining) {
+                            (Some(t), Some(r)) => Some(t.min(r)),
+                            (t, r) => t.or(r),
+                        };
+                        peer.options.connection_timeout = cap(timeouts.connect);
+                        peer.options.total_connection_timeout = cap(timeouts.connect);
+                        peer.options.read_timeout = cap(timeouts.read);
+                        peer.options.write_timeout = cap(timeouts.write);
+                        peer.options.idle_timeout = timeouts.idle;
                     }
                     /*
                     Experimental optionsv
@@ -181,7 +309,7 @@ impl ProxyHttp for LB {
                     peer.options.tcp_recv_buf = Some(128 * 1024);
                     End of experimental options
                     */
-                    if let Some(_) = ctx.extraparams.sticky_sessions {
+                    if ctx.extraparams.sticky_sessions.is_some() {
                         let mut s = String::with_capacity(64);
                         write!(
                             &mut s,
@@ -202,7 +330,7 @@ impl ProxyHttp for LB {
                     Ok(peer)
                 }
                 None => {
-                    if let Err(e) = session.respond_error_with_body(502, Bytes::from("502 Bad Gateway\n")).await {
+                    if let Err(e) = send_error(session, ctx, 502).await {
                         error!("Failed to send error response: {:?}", e);
                     }
                     Err(Box::new(Error {
@@ -215,7 +343,7 @@ impl ProxyHttp for LB {
                 }
             },
             None => {
-                if let Err(e) = session.respond_error_with_body(502, Bytes::from("502 Bad Gateway\n")).await {
+                if let Err(e) = send_error(session, ctx, 502).await {
                     error!("Failed to send error response: {:?}", e);
                 }
                 Err(Box::new(Error {
@@ -229,34 +357,172 @@ impl ProxyHttp for LB {
         }
     }
 
+    fn fail_to_connect(&self, session: &mut Session, _peer: &HttpPeer, ctx: &mut Self::CTX, mut e: Box<Error>) -> Box<Error> {
+        if let Some(backend) = ctx.upstream_peer.as_ref() {
+            self.record_failure(ctx.hostname.as_deref(), backend, true);
+            if backend.retry.is_some() {
+                let retry = self.schedule_retry(session, ctx, RetryReason::ConnectFailure);
+                e.set_retry(retry);
+            }
+        }
+        e
+    }
+
+    fn error_while_proxy(&self, peer: &HttpPeer, session: &mut Session, e: Box<Error>, ctx: &mut Self::CTX, client_reused: bool) -> Box<Error> {
+        if !client_reused && matches!(e.esource(), Upstream) {
+            if let Some(backend) = ctx.upstream_peer.as_ref() {
+                self.record_failure(ctx.hostname.as_deref(), backend, false);
+            }
+        }
+        let timed_out = matches!(e.etype(), ReadTimedout | WriteTimedout);
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry.decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        // Nothing has been sent downstream yet, so the request can be replayed on another backend
+        if timed_out && ctx.upstream_status.is_none() && !e.retry() && self.schedule_retry(session, ctx, RetryReason::Timeout) {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> FailToProxy {
+        let request_expired = request_expired(ctx);
+        let timeout = match e.etype() {
+            _ if !matches!(e.esource(), Upstream) => None,
+            ConnectTimedout | TLSHandshakeTimedout | ReadTimedout | WriteTimedout if request_expired => Some("request"),
+            ConnectTimedout | TLSHandshakeTimedout => Some("connect"),
+            ReadTimedout => Some("read"),
+            WriteTimedout => Some("write"),
+            _ => None,
+        };
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ if timeout.is_some() => 504,
+            _ => match e.esource() {
+                Upstream => 502,
+                Downstream => match e.etype() {
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                Internal | Unset => 500,
+            },
+        };
+        if let Some(kind) = timeout {
+            UPSTREAM_TIMEOUTS.with_label_values(&[kind]).inc();
+        }
+        if code > 0 && session.response_written().is_none() {
+            if let Err(e) = send_error(session, ctx, code).await {
+                error!("Failed to send error response: {:?}", e);
+            }
+        }
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     async fn upstream_request_filter(&self, session: &mut Session, upstream_request: &mut RequestHeader, ctx: &mut Self::CTX) -> Result<()> {
-        if let Some(ip) = session.client_addr().and_then(|a| a.as_inet()).map(|i| i.ip()) {
-            IP_BUFFER.with(|buffer| {
-                let mut buf = buffer.borrow_mut();
-                buf.clear();
-                write!(buf, "{}", ip).unwrap_or(());
-                upstream_request.append_header("X-Forwarded-For", buf.as_str()).unwrap_or(false);
-            });
+        forwarding_headers(session, upstream_request);
+        upstream_request.insert_header(request_id_header(), ctx.request_id.as_str())?;
+        if let Some(backend) = ctx.upstream_peer.as_ref() {
+            if let Some(rewrite) = backend.rewrite.as_ref() {
+                let uri = &session.req_header().uri;
+                let mut target = rewrite_path(rewrite, uri.path()).into_owned();
+                if let Some(query) = uri.query() {
+                    target.push('?');
+                    target.push_str(query);
+                }
+                match target.parse::<Uri>() {
+                    Ok(new_uri) => upstream_request.set_uri(new_uri),
+                    Err(e) => warn!("Rewritten URI {} is invalid: {}", target, e),
+                }
+            }
+            if let Some(host) = backend.host_header.as_deref() {
+                upstream_request.insert_header("Host", expand_captures(host, &ctx.host_captures).as_ref())?;
+            }
         }
 
         let hostname = ctx.hostname.as_deref().unwrap_or("localhost");
         let path = session.req_header().uri.path();
-        let GetHostsReturHeaders { server_headers, client_headers } = match self.get_header(hostname, path) {
-            Some(h) => h,
-            None => return Ok(()),
-        };
+        if let Some(GetHostsReturHeaders { server_headers, client_headers }) = self.get_header(hostname, path, session) {
+            if let Some(sh) = server_headers {
+                // Status conditions only make sense on responses
+                for rule in sh.iter().filter(|r| r.status.is_empty()) {
+                    match rule.op {
+                        HeaderOp::Remove => {
+                            upstream_request.remove_header(rule.name.as_str());
+                        }
+                        HeaderOp::Append => {
+                            upstream_request.append_header(rule.name.clone(), expand_variables(&rule.value, session, ctx).as_ref())?;
+                        }
+                        HeaderOp::Set => upstream_request.insert_header(rule.name.clone(), expand_variables(&rule.value, session, ctx).as_ref())?,
+                    };
+                }
+            }
+            if let Some(ch) = client_headers {
+                ctx.client_headers = Some(ch);
+            }
+        }
 
-        if let Some(sh) = server_headers {
-            for (k, v) in sh {
-                upstream_request.insert_header(k, v.as_ref())?;
+        // Sampled once per request, retries to other upstreams are not mirrored again
+        if !ctx.mirror_sampled {
+            ctx.mirror_sampled = true;
+            if let Some(params) = ctx.upstream_peer.as_ref().and_then(|b| b.mirror.as_ref()) {
+                let sni = upstream_request.headers.get("host").and_then(|h| h.to_str().ok()).unwrap_or(hostname);
+                if let Some(mirror) = MirrorRequest::sample(params, upstream_request, sni) {
+                    if session.is_body_empty() {
+                        mirror.send();
+                    } else {
+                        ctx.mirror = Some(mirror);
+                    }
+                }
             }
         }
-        if let Some(ch) = client_headers {
-            ctx.client_headers = Some(ch);
+        Ok(())
+    }
+    async fn request_body_filter(&self, _session: &mut Session, body: &mut Option<Bytes>, end_of_stream: bool, ctx: &mut Self::CTX) -> Result<()> {
+        check_deadline(ctx)?;
+        if let Some(mirror) = ctx.mirror.as_mut() {
+            if let Some(chunk) = body.as_ref() {
+                mirror.push_body(chunk);
+            }
+            if end_of_stream {
+                if let Some(mirror) = ctx.mirror.take() {
+                    mirror.send();
+                }
+            }
         }
         Ok(())
     }
     async fn response_filter(&self, _session: &mut Session, _upstream_response: &mut ResponseHeader, ctx: &mut Self::CTX) -> Result<()> {
+        check_deadline(ctx)?;
+        let status = _upstream_response.status.as_u16();
+        if status >= 500 && ctx.upstream_peer.as_ref().is_some_and(|b| b.retry.is_some()) {
+            let failed = ctx.upstream_peer.clone();
+            if self.schedule_retry(_session, ctx, RetryReason::Status5xx) {
+                if let Some(backend) = failed.as_ref() {
+                    self.record_failure(ctx.hostname.as_deref(), backend, false);
+                }
+                let mut e = Error::explain(HTTPStatus(status), "Upstream 5xx, retrying on another upstream");
+                e.set_retry(true);
+                return Err(e);
+            }
+        }
+        ctx.upstream_status = Some(status);
+        if status >= 500 {
+            let intercept = error_pages(ctx).filter(|p| p.intercept_5xx).is_some_and(|p| p.page(status).is_some() || p.json);
+            if intercept {
+                // Nothing is sent downstream yet, fail_to_proxy answers with the page in place of the upstream response,
+                // whether the upstream body is empty or not
+                return Err(Error::explain(HTTPStatus(status), "Upstream 5xx replaced with the error page"));
+            }
+        }
+        if let Some(cache) = ctx.cache.as_mut() {
+            cache.response_header(_session.req_header(), _upstream_response);
+        }
+        if let Some(cache_status) = ctx.cache_status {
+            _upstream_response.insert_header("X-Cache", cache_status)?;
+        }
+        _upstream_response.insert_header(request_id_header(), ctx.request_id.as_str())?;
         if let Some(val) = ctx.extraparams.sticky_sessions {
             if let Some(bid) = &ctx.backend_id {
                 let tt = if let Some(existing) = REVERSE_STORE.get(bid) {
@@ -282,14 +548,40 @@ impl ProxyHttp for LB {
         }
 
         if let Some(client_headers) = &ctx.client_headers {
-            for (k, v) in client_headers.iter() {
-                _upstream_response.append_header(k.clone(), v.as_ref())?;
+            apply_client_headers(client_headers, _upstream_response, status, _session, ctx)?;
+        }
+        if let Some(params) = ctx.upstream_peer.as_ref().and_then(|b| b.compression.as_ref()) {
+            ctx.compression = Compressor::new(params, _session.req_header());
+            if let Some(compressor) = ctx.compression.as_mut() {
+                compressor.response_header(_upstream_response);
             }
         }
         Ok(())
     }
+    fn response_body_filter(&self, _session: &mut Session, body: &mut Option<Bytes>, end_of_stream: bool, ctx: &mut Self::CTX) -> Result<Option<Duration>> {
+        if !end_of_stream {
+            check_deadline(ctx)?;
+        }
+        // The cache keeps the body as the upstream sent it
+        if let Some(cache) = ctx.cache.as_mut() {
+            cache.response_body(body, end_of_stream);
+        }
+        if let Some(compressor) = ctx.compression.as_mut() {
+            compressor.response_body(body, end_of_stream);
+        }
+        Ok(None)
+    }
 
     async fn logging(&self, session: &mut Session, _e: Option<&pingora::Error>, ctx: &mut Self::CTX) {
+        if let (Some(backend), Some(status)) = (ctx.upstream_peer.as_ref(), ctx.upstream_status) {
+            if let Some(params) = backend.outlier.as_ref() {
+                if status >= 500 {
+                    self.record_failure(ctx.hostname.as_deref(), backend, false);
+                } else {
+                    backend.state.record_success(params);
+                }
+            }
+        }
         let response_code = session.response_written().map_or(0, |resp| resp.status.as_u16());
         let m = &MetricTypes {
             method: session.req_header().method.clone(),
@@ -299,20 +591,213 @@ impl ProxyHttp for LB {
             upstream: ctx.hostname.take().unwrap_or_else(|| LOCALHOST.clone()),
         };
         calc_metrics(m);
+        if let Some(compressor) = ctx.compression.take() {
+            compressor.finish();
+        }
         ACTIVE_SESSIONS.dec();
-        if let Some(_) = ctx.x4xx_limit.or(ctx.extraparams.x4xx_limit) {
-            if (400..=499).contains(&response_code) {
-                if let Some(ip) = session.client_addr().and_then(|a| a.as_inet()).map(|i| i.ip()) {
-                    let current = REQUESTS_4XX.get(&ip).unwrap_or(0);
-                    REQUESTS_4XX.insert(ip, current + 1);
-                }
+        if let Some(backend) = ctx.in_flight.take() {
+            backend.state.in_flight.fetch_sub(1, Ordering::Relaxed);
+            if let Some(start) = ctx.upstream_start {
+                backend.state.observe_latency(start.elapsed());
+            }
+        }
+        if ctx.x4xx_limit.or(ctx.extraparams.x4xx_limit).is_some() && (400..=499).contains(&response_code) {
+            if let Some(ip) = client_ip(session) {
+                let current = REQUESTS_4XX.get(&ip).unwrap_or(0);
+                REQUESTS_4XX.insert(ip, current + 1);
+            }
+        }
+        access_log(response_code, &self.request_summary(session, ctx), &ctx.request_id, session);
+    }
+}
+
+impl LB {
+    // Sends a cached response with the client headers and compression of its path, like a proxied one
+    async fn serve_cached(&self, session: &mut Session, ctx: &mut Context, backend: &InnerMap, object: &CachedObject, status: &'static str) -> Result<bool> {
+        let mut resp = object.header.clone();
+        let code = resp.status.as_u16();
+        resp.insert_header("Age", object.age().to_string())?;
+        resp.insert_header("X-Cache", status)?;
+        resp.insert_header(request_id_header(), ctx.request_id.as_str())?;
+        let hostname = ctx.hostname.clone().unwrap_or_else(|| LOCALHOST.clone());
+        if let Some(rules) = self.get_header(&hostname, session.req_header().uri.path(), session).and_then(|h| h.client_headers) {
+            apply_client_headers(&rules, &mut resp, code, session, ctx)?;
+        }
+        let mut body = Some(object.body.clone());
+        if let Some(params) = backend.compression.as_ref() {
+            ctx.compression = Compressor::new(params, session.req_header());
+            if let Some(compressor) = ctx.compression.as_mut() {
+                compressor.response_header(&mut resp);
+                compressor.response_body(&mut body, true);
+            }
+        }
+        if session.req_header().method == Method::HEAD || object.body.is_empty() {
+            session.write_response_header(Box::new(resp), true).await?;
+        } else {
+            session.write_response_header(Box::new(resp), false).await?;
+            session.write_response_body(body, true).await?;
+        }
+        Ok(true)
+    }
+
+    // Moves the request to a not yet tried live backend of the same path, if the path retry policy allows it
+    fn schedule_retry(&self, session: &Session, ctx: &mut Context, reason: RetryReason) -> bool {
+        let Some(backend) = ctx.upstream_peer.clone() else {
+            return false;
+        };
+        let Some(params) = backend.retry.as_ref() else {
+            return false;
+        };
+        if !reason.enabled(params) || ctx.tried.len() >= params.retries as usize {
+            return false;
+        }
+        if !params.non_idempotent && !is_idempotent(&session.req_header().method) {
+            return false;
+        }
+        if reason != RetryReason::ConnectFailure && session.as_ref().retry_buffer_truncated() {
+            return false;
+        }
+        let Some(pool) = ctx.hostname.as_deref().and_then(|h| self.live_pool(h, &backend)) else {
+            return false;
+        };
+        ctx.tried.push(backend);
+        let candidates: Vec<Arc<InnerMap>> = pool.into_iter().filter(|s| !ctx.tried.iter().any(|t| Arc::ptr_eq(&t.state, &s.state))).collect();
+        if candidates.is_empty() {
+            return false;
+        }
+        // The path's load balancing method picks among the backends not tried yet, skipping ejected ones
+        let index = AtomicUsize::new(rand::rng().random_range(0..candidates.len()));
+        let Some(next) = self.pick_backend(&candidates, &index, None, session) else {
+            return false;
+        };
+        UPSTREAM_RETRIES.with_label_values(&[reason.as_str()]).inc();
+        ctx.upstream_peer = Some(next);
+        true
+    }
+
+    // Passive health checking, ejects a backend after too many consecutive errors of live traffic
+    fn record_failure(&self, host: Option<&str>, backend: &Arc<InnerMap>, connect: bool) {
+        let Some(params) = backend.outlier.as_ref() else {
+            return;
+        };
+        let (count, limit, reason) = if connect {
+            let count = backend.state.consecutive_connect_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            (count, params.consecutive_connect_failures, "connect_failure")
+        } else {
+            let count = backend.state.consecutive_5xx.fetch_add(1, Ordering::Relaxed) + 1;
+            (count, params.consecutive_5xx, "5xx")
+        };
+        if count < limit || backend.state.is_ejected() {
+            return;
+        }
+        if let Some(pool) = host.and_then(|h| self.configured_pool(h, backend)) {
+            let ejected = pool.iter().filter(|s| s.state.is_ejected()).count();
+            // One upstream can always be ejected, like in Envoy, so small pools and percentages still work
+            if ejected > 0 && ejected * 100 >= pool.len() * params.max_ejection_percent as usize {
+                warn!("Not ejecting upstream {}:{}, max_ejection_percent reached", backend.address, backend.port);
+                return;
             }
         }
-        access_log(response_code, &self.request_summary(session, ctx), session);
+        let period = backend.state.eject(params);
+        UPSTREAM_EJECTIONS.with_label_values(&[reason]).inc();
+        warn!(
+            "Ejecting upstream {}:{} for {:?} after {} consecutive {} errors",
+            backend.address, backend.port, period, count, reason
+        );
+    }
+}
+
+fn with_query(mut location: String, query: Option<&str>) -> String {
+    if let Some(query) = query.filter(|q| !q.is_empty()) {
+        location.push(if location.contains('?') { '&' } else { '?' });
+        location.push_str(query);
     }
+    location
 }
 
-fn return_header_host_from_upstream(session: &Session, ump_upst: &UpstreamsDashMap) -> Option<Arc<str>> {
+fn request_expired(ctx: &Context) -> bool {
+    ctx.upstream_peer
+        .as_ref()
+        .and_then(|b| b.timeouts.as_ref())
+        .and_then(|t| t.request)
+        .is_some_and(|t| ctx.start_time.elapsed() >= t)
+}
+
+// Per-operation timeouts can't stop a body which keeps trickling in, so request_timeout is checked on every chunk too
+fn check_deadline(ctx: &Context) -> Result<()> {
+    if request_expired(ctx) {
+        return Err(Error::explain(ReadTimedout, "request_timeout exceeded").into_up());
+    }
+    Ok(())
+}
+
+async fn send_redirect(session: &mut Session, ctx: &Context, code: u16, location: String) -> Result<bool> {
+    let mut resp = ResponseHeader::build(code, None)?;
+    resp.insert_header("Location", location)?;
+    resp.insert_header(request_id_header(), ctx.request_id.as_str())?;
+    match error_pages(ctx).and_then(|p| p.page(code)).map(|page| render_page(page, code, session, ctx)) {
+        Some(body) => {
+            resp.insert_header("Content-Type", "text/html; charset=utf-8")?;
+            resp.insert_header("Content-Length", body.len().to_string())?;
+            session.write_response_header(Box::new(resp), false).await?;
+            session.write_response_body(Some(Bytes::from(body)), true).await?;
+        }
+        None => {
+            resp.insert_header("Content-Length", "0")?;
+            session.write_response_header(Box::new(resp), true).await?;
+        }
+    }
+    Ok(true)
+}
+
+// Error response generated by the proxy itself, with the error page of the path if there is one
+async fn send_error(session: &mut Session, ctx: &Context, code: u16) -> Result<()> {
+    let (body, content_type) = error_pages(ctx)
+        .and_then(|p| error_body(p, code, session, ctx))
+        .unwrap_or_else(|| (format!("{} {}\n", code, reason(code)), "text/plain; charset=utf-8"));
+    let mut resp = ResponseHeader::build(code, None)?;
+    resp.insert_header("Content-Type", content_type)?;
+    resp.insert_header("Content-Length", body.len().to_string())?;
+    resp.insert_header("Cache-Control", "private, no-store")?;
+    resp.insert_header(request_id_header(), ctx.request_id.as_str())?;
+    session.write_response_header(Box::new(resp), false).await?;
+    session.write_response_body(Some(Bytes::from(body)), true).await
+}
+
+fn error_pages(ctx: &Context) -> Option<&ErrorPages> {
+    ctx.path_settings.as_ref().and_then(|s| s.error_pages.as_deref())
+}
+
+// Page configured for the status, or the default JSON error for JSON hosts
+fn error_body(pages: &ErrorPages, code: u16, session: &Session, ctx: &Context) -> Option<(String, &'static str)> {
+    let content_type = if pages.json { "application/json" } else { "text/html; charset=utf-8" };
+    match pages.page(code) {
+        Some(page) => Some((render_page(page, code, session, ctx), content_type)),
+        None if pages.json => Some((
+            serde_json::json!({"status": code, "error": reason(code), "request_id": ctx.request_id}).to_string(),
+            content_type,
+        )),
+        None => None,
+    }
+}
+
+// Templates can use $status and $reason besides the header variables like $request_id and $host
+fn render_page(page: &str, code: u16, session: &Session, ctx: &Context) -> String {
+    let page = page.replace("$status", &code.to_string()).replace("$reason", reason(code));
+    expand_variables(&page, session, ctx).into_owned()
+}
+
+fn reason(code: u16) -> &'static str {
+    StatusCode::from_u16(code).ok().and_then(|s| s.canonical_reason()).unwrap_or("Error")
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE)
+}
+
+// Resolves the upstream key for the request host: exact match, then wildcard and regex hosts, then DEFAULT.
+// Returns the key together with the actual request host; pattern captures are stored for `$host_N` expansion.
+fn return_header_host_from_upstream(session: &Session, ump_upst: &UpstreamsDashMap, patterns: &[HostPattern], captures: &mut Vec<String>) -> Option<(Arc<str>, Arc<str>)> {
     let host_str = if session.is_http2() {
         session.req_header().uri.host()?
     } else {
@@ -320,5 +805,175 @@ fn return_header_host_from_upstream(session: &Session, ump_upst: &UpstreamsDashM
         h.split_once(':').map_or(h, |(host, _)| host)
     };
 
-    ump_upst.get(host_str).or_else(|| ump_upst.get("DEFAULT")).map(|entry| entry.key().clone())
+    if let Some(entry) = ump_upst.get(host_str) {
+        return Some((entry.key().clone(), entry.key().clone()));
+    }
+    for pattern in patterns {
+        if let Some(caps) = pattern.regex.captures(host_str) {
+            if ump_upst.contains_key(&pattern.key) {
+                captures.extend(caps.iter().skip(1).map(|c| c.map_or(String::new(), |m| m.as_str().to_string())));
+                return Some((pattern.key.clone(), Arc::from(host_str)));
+            }
+        }
+    }
+    ump_upst.get("DEFAULT").map(|entry| (entry.key().clone(), entry.key().clone()))
+}
+
+fn expand_captures<'a>(value: &'a str, captures: &[String]) -> Cow<'a, str> {
+    if captures.is_empty() || !value.contains("$host_") {
+        return Cow::Borrowed(value);
+    }
+    let mut out = value.to_string();
+    for (n, capture) in captures.iter().enumerate().rev() {
+        out = out.replace(&format!("$host_{}", n + 1), capture);
+    }
+    Cow::Owned(out)
+}
+
+fn apply_client_headers(rules: &[HeaderRule], resp: &mut ResponseHeader, status: u16, session: &Session, ctx: &Context) -> Result<()> {
+    for rule in rules.iter().filter(|r| r.matches(status)) {
+        match rule.op {
+            HeaderOp::Remove => {
+                resp.remove_header(rule.name.as_str());
+            }
+            HeaderOp::Append => {
+                resp.append_header(rule.name.clone(), expand_variables(&rule.value, session, ctx).as_ref())?;
+            }
+            HeaderOp::Set => resp.insert_header(rule.name.clone(), expand_variables(&rule.value, session, ctx).as_ref())?,
+        };
+    }
+    Ok(())
+}
+
+// Header values can hold $host_N captures and request variables, unknown names are kept as they are
+fn expand_variables<'a>(value: &'a str, session: &Session, ctx: &Context) -> Cow<'a, str> {
+    substitute_variables(value, |name| header_variable(name, session, ctx))
+}
+
+fn substitute_variables<'a>(value: &'a str, lookup: impl Fn(&str) -> Option<String>) -> Cow<'a, str> {
+    if !value.contains('$') {
+        return Cow::Borrowed(value);
+    }
+    let mut out = String::with_capacity(value.len() + 32);
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos + 1..];
+        let len = tail.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(tail.len());
+        match lookup(&tail[..len]) {
+            Some(v) => out.push_str(&v),
+            None => {
+                out.push('$');
+                out.push_str(&tail[..len]);
+            }
+        }
+        rest = &tail[len..];
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+fn header_variable(name: &str, session: &Session, ctx: &Context) -> Option<String> {
+    let value = match name {
+        "client_ip" => client_ip(session).map(|ip| ip.to_string()),
+        "request_id" => Some(ctx.request_id.clone()),
+        "host" => ctx.matched_host.as_deref().or(ctx.hostname.as_deref()).map(str::to_string),
+        "upstream_addr" => ctx.upstream_peer.as_ref().map(|b| format!("{}:{}", b.address, b.port)),
+        "tls_version" => session.digest().and_then(|d| d.ssl_digest.as_ref()).map(|ssl| ssl.version.to_string()),
+        _ => {
+            let n: usize = name.strip_prefix("host_")?.parse().ok()?;
+            return ctx.host_captures.get(n.checked_sub(1)?).cloned();
+        }
+    };
+    Some(value.unwrap_or_default())
+}
+
+// strip_prefix removes whole path segments only, the rewritten path always starts with '/'
+fn rewrite_path<'a>(rewrite: &UriRewrite, path: &'a str) -> Cow<'a, str> {
+    let mut out = Cow::Borrowed(path);
+    if let Some(rest) = rewrite.strip_prefix.as_deref().and_then(|prefix| path.strip_prefix(prefix)) {
+        if rest.is_empty() {
+            out = Cow::Borrowed("/");
+        } else if rest.starts_with('/') {
+            out = Cow::Borrowed(rest);
+        }
+    }
+    if let Some((pattern, replacement)) = rewrite.regex.as_ref() {
+        if pattern.0.is_match(&out) {
+            out = Cow::Owned(pattern.0.replace(&out, replacement.as_ref()).into_owned());
+        }
+    }
+    if let Some(prefix) = rewrite.add_prefix.as_deref() {
+        out = Cow::Owned(format!("{}{}", prefix, out));
+    }
+    if !out.starts_with('/') {
+        out = Cow::Owned(format!("/{}", out));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::structs::Pattern;
+    use regex::Regex;
+
+    fn rewrite(strip_prefix: Option<&str>, regex: Option<(&str, &str)>, add_prefix: Option<&str>) -> UriRewrite {
+        UriRewrite {
+            strip_prefix: strip_prefix.map(Arc::from),
+            regex: regex.map(|(re, replacement)| (Pattern(Regex::new(re).unwrap()), Arc::from(replacement))),
+            add_prefix: add_prefix.map(Arc::from),
+        }
+    }
+
+    #[test]
+    fn rewrite_path_steps() {
+        let strip = rewrite(Some("/api"), None, None);
+        assert_eq!(rewrite_path(&strip, "/api/users"), "/users");
+        assert_eq!(rewrite_path(&strip, "/api"), "/");
+        assert_eq!(rewrite_path(&strip, "/apix/users"), "/apix/users");
+        let regex = rewrite(None, Some(("^/v(?P<version>[0-9]+)/(.*)$", "/api/$version/$2")), None);
+        assert_eq!(rewrite_path(&regex, "/v2/items"), "/api/2/items");
+        assert_eq!(rewrite_path(&regex, "/items"), "/items");
+        let all = rewrite(Some("/public"), Some(("^/old/", "/new/")), Some("/static"));
+        assert_eq!(rewrite_path(&all, "/public/old/a.css"), "/static/new/a.css");
+        let relative = rewrite(None, Some(("^/", "")), None);
+        assert_eq!(rewrite_path(&relative, "/x"), "/x");
+    }
+
+    #[test]
+    fn retry_reasons_follow_the_policy() {
+        let params = RetryParams {
+            retries: 2,
+            on_connect_failure: true,
+            on_5xx: false,
+            on_timeout: true,
+            non_idempotent: false,
+        };
+        let reasons = [RetryReason::ConnectFailure, RetryReason::Timeout, RetryReason::Status5xx];
+        assert_eq!(reasons.map(|r| r.enabled(&params)), [true, true, false]);
+        assert_eq!(reasons.map(|r| r.as_str()), ["connect_failure", "timeout", "5xx"]);
+    }
+
+    #[test]
+    fn substitute_variables_keeps_unknown_names() {
+        let lookup = |name: &str| match name {
+            "client_ip" => Some("203.0.113.5".to_string()),
+            "host_1" => Some("shop".to_string()),
+            "tls_version" => Some(String::new()),
+            _ => None,
+        };
+        let cases = [
+            ("plain", "plain"),
+            ("$client_ip", "203.0.113.5"),
+            ("for=$client_ip;by=$host_1.example", "for=203.0.113.5;by=shop.example"),
+            ("tls=$tls_version", "tls="),
+            ("$unknown and $host_2", "$unknown and $host_2"),
+            ("cost $ 5$", "cost $ 5$"),
+            ("$$client_ip", "$203.0.113.5"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(substitute_variables(value, lookup), expected, "{}", value);
+        }
+    }
 }
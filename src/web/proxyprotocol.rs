@@ -0,0 +1,270 @@
+use async_trait::async_trait;
+use log::{debug, warn};
+use pingora::tls::ssl::SslAcceptor;
+use pingora_core::apps::ServerApp;
+use pingora_core::connectors::L4Connect;
+use pingora_core::protocols::l4::socket::SocketAddr as PingoraAddr;
+use pingora_core::protocols::l4::stream::Stream as L4Stream;
+use pingora_core::protocols::tls::server::handshake;
+use pingora_core::protocols::{SocketDigest, Stream};
+use pingora_core::server::ShutdownWatch;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Listener app which reads the PROXY protocol v1/v2 header first and hands the connection to the proxy
+// with the real client address. TLS, if any, is negotiated after the header, as the balancer sends it in clear.
+pub struct ProxyProtocolApp<A> {
+    inner: Arc<A>,
+    tls: Option<SslAcceptor>,
+}
+
+impl<A> ProxyProtocolApp<A> {
+    pub fn new(inner: A, tls: Option<SslAcceptor>) -> Self {
+        Self { inner: Arc::new(inner), tls }
+    }
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> ServerApp for ProxyProtocolApp<A> {
+    async fn process_new(self: &Arc<Self>, mut stream: Stream, shutdown: &ShutdownWatch) -> Option<Stream> {
+        let addresses = match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+            Ok(Ok(addresses)) => addresses,
+            Ok(Err(e)) => {
+                warn!("Dropping connection with invalid PROXY protocol header: {}", e);
+                return None;
+            }
+            Err(_) => {
+                warn!("Dropping connection, no PROXY protocol header in {:?}", HEADER_TIMEOUT);
+                return None;
+            }
+        };
+        if let Some((source, destination)) = addresses {
+            let digest = SocketDigest::from_raw_fd(stream.id());
+            let _ = digest.peer_addr.set(Some(PingoraAddr::Inet(source)));
+            let _ = digest.local_addr.set(Some(PingoraAddr::Inet(destination)));
+            stream.set_socket_digest(digest);
+        }
+        let stream: Stream = match &self.tls {
+            Some(acceptor) => {
+                // Listeners hand over plain TCP streams, the handshake needs the concrete type
+                let Ok(tcp) = stream.into_any().downcast::<L4Stream>() else {
+                    warn!("Dropping connection, TLS after PROXY protocol header needs a TCP stream");
+                    return None;
+                };
+                match handshake(acceptor, *tcp).await {
+                    Ok(tls_stream) => Box::new(tls_stream),
+                    Err(e) => {
+                        debug!("TLS handshake after PROXY protocol header failed: {}", e);
+                        return None;
+                    }
+                }
+            }
+            None => stream,
+        };
+        self.inner.process_new(stream, shutdown).await
+    }
+
+    async fn cleanup(&self) {
+        self.inner.cleanup().await
+    }
+}
+
+// (source, destination) of the proxied connection, None for LOCAL and UNKNOWN connections
+async fn read_header(stream: &mut Stream) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let mut head = [0u8; 16];
+    stream.read_exact(&mut head[..8]).await.map_err(|e| e.to_string())?;
+    if head[..8] == V2_SIGNATURE[..8] {
+        stream.read_exact(&mut head[8..]).await.map_err(|e| e.to_string())?;
+        if head[..12] != V2_SIGNATURE || head[12] >> 4 != 2 {
+            return Err("bad v2 signature".to_string());
+        }
+        let mut body = vec![0u8; u16::from_be_bytes([head[14], head[15]]) as usize];
+        stream.read_exact(&mut body).await.map_err(|e| e.to_string())?;
+        return Ok(parse_v2(head[12] & 0x0F, head[13], &body));
+    }
+    if &head[..6] != b"PROXY " {
+        return Err("missing header".to_string());
+    }
+    let mut line = head[..8].to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err("v1 header too long".to_string());
+        }
+        line.push(stream.read_u8().await.map_err(|e| e.to_string())?);
+    }
+    parse_v1(std::str::from_utf8(&line).map_err(|e| e.to_string())?)
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n" or "PROXY UNKNOWN\r\n"
+fn parse_v1(line: &str) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let parts: Vec<&str> = line.trim_end().split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "TCP4" | "TCP6", src, dst, sport, dport] => {
+            let addr = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+                let ip: IpAddr = ip.parse().map_err(|_| format!("bad address {}", ip))?;
+                let port: u16 = port.parse().map_err(|_| format!("bad port {}", port))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some((addr(src, sport)?, addr(dst, dport)?)))
+        }
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => Err(format!("bad v1 header {:?}", line.trim_end())),
+    }
+}
+
+fn parse_v2(command: u8, family: u8, body: &[u8]) -> Option<(SocketAddr, SocketAddr)> {
+    // LOCAL command is sent by the balancer for its own health checks
+    if command != 1 {
+        return None;
+    }
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            let (sport, dport) = (u16::from_be_bytes([body[8], body[9]]), u16::from_be_bytes([body[10], body[11]]));
+            Some((SocketAddr::new(IpAddr::V4(src), sport), SocketAddr::new(IpAddr::V4(dst), dport)))
+        }
+        2 if body.len() >= 36 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).ok()?);
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&body[16..32]).ok()?);
+            let (sport, dport) = (u16::from_be_bytes([body[32], body[33]]), u16::from_be_bytes([body[34], body[35]]));
+            Some((SocketAddr::new(IpAddr::V6(src), sport), SocketAddr::new(IpAddr::V6(dst), dport)))
+        }
+        _ => None,
+    }
+}
+
+// Connects to an upstream and sends the PROXY protocol v2 header of the client connection before anything else.
+// Without addresses, e.g. for clients on unix sockets and health checks, a LOCAL header is sent.
+#[derive(Debug)]
+pub struct ProxyV2Connect {
+    pub addresses: Option<(SocketAddr, SocketAddr)>,
+}
+
+#[async_trait]
+impl L4Connect for ProxyV2Connect {
+    async fn connect(&self, addr: &PingoraAddr) -> pingora::Result<L4Stream> {
+        let PingoraAddr::Inet(inet) = addr else {
+            return pingora::Error::e_explain(pingora::ErrorType::SocketError, "PROXY protocol needs an inet upstream");
+        };
+        let tcp = TcpStream::connect(*inet)
+            .await
+            .map_err(|e| pingora::Error::because(pingora::ErrorType::ConnectError, "connecting upstream", e))?;
+        let mut stream = L4Stream::from(tcp);
+        stream
+            .write_all(&v2_header(self.addresses))
+            .await
+            .map_err(|e| pingora::Error::because(pingora::ErrorType::WriteError, "writing PROXY protocol header", e))?;
+        Ok(stream)
+    }
+}
+
+fn v2_header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(52);
+    header.extend_from_slice(&V2_SIGNATURE);
+    let Some((source, destination)) = addresses else {
+        // LOCAL command, UNSPEC family, no addresses
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        return header;
+    };
+    header.push(0x21);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&v6(src).octets());
+            header.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_tcp4_and_tcp6() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"),
+            Ok(Some((addr("192.0.2.1:56324"), addr("198.51.100.1:443"))))
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n"),
+            Ok(Some((addr("[2001:db8::1]:56324"), addr("[2001:db8::2]:443"))))
+        );
+    }
+
+    #[test]
+    fn v1_unknown_and_invalid() {
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n"), Ok(None));
+        assert_eq!(parse_v1("PROXY UNKNOWN 192.0.2.1 198.51.100.1 56324 443\r\n"), Ok(None));
+        assert!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.300 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n").is_err());
+        assert!(parse_v1("GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn v2_roundtrip() {
+        for (source, destination) in [
+            (addr("192.0.2.1:56324"), addr("198.51.100.1:443")),
+            (addr("[2001:db8::1]:56324"), addr("[2001:db8::2]:443")),
+        ] {
+            let header = v2_header(Some((source, destination)));
+            assert_eq!(header[..12], V2_SIGNATURE);
+            assert_eq!(u16::from_be_bytes([header[14], header[15]]) as usize, header.len() - 16);
+            assert_eq!(parse_v2(header[12] & 0x0F, header[13], &header[16..]), Some((source, destination)));
+        }
+    }
+
+    #[test]
+    fn v2_mixed_families_use_ipv6() {
+        let header = v2_header(Some((addr("192.0.2.1:56324"), addr("[2001:db8::2]:443"))));
+        assert_eq!(header[13], 0x21);
+        assert_eq!(
+            parse_v2(header[12] & 0x0F, header[13], &header[16..]),
+            Some((addr("[::ffff:192.0.2.1]:56324"), addr("[2001:db8::2]:443")))
+        );
+    }
+
+    #[test]
+    fn v2_local() {
+        let header = v2_header(None);
+        assert_eq!(header.len(), 16);
+        assert_eq!(header[12], 0x20);
+        assert_eq!(parse_v2(header[12] & 0x0F, header[13], &header[16..]), None);
+        // LOCAL connections carry no client address even if the body has one
+        let proxied = v2_header(Some((addr("192.0.2.1:56324"), addr("198.51.100.1:443"))));
+        assert_eq!(parse_v2(0, proxied[13], &proxied[16..]), None);
+    }
+
+    #[test]
+    fn v2_short_body() {
+        assert_eq!(parse_v2(1, 0x11, &[192, 0, 2, 1]), None);
+        assert_eq!(parse_v2(1, 0x21, &[0; 12]), None);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::web::forwarding::from_trusted_proxy;
+use log::{info, warn};
+use pingora_proxy::Session;
+use rand::RngExt;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static REQUEST_ID: OnceLock<RequestIdConfig> = OnceLock::new();
+const MAX_INCOMING_LEN: usize = 128;
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdFormat {
+    Uuid4,
+    Uuid7,
+    Ulid,
+}
+
+#[derive(Debug)]
+struct RequestIdConfig {
+    header: String,
+    format: IdFormat,
+}
+
+pub fn init_request_id(header: Option<&str>, format: Option<&str>) {
+    let format = match format.map(|f| f.to_ascii_lowercase()).as_deref() {
+        None | Some("uuid4") | Some("uuidv4") => IdFormat::Uuid4,
+        Some("uuid7") | Some("uuidv7") => IdFormat::Uuid7,
+        Some("ulid") => IdFormat::Ulid,
+        Some(other) => {
+            warn!("Unknown request ID format: {}, defaulting to: uuid4", other);
+            IdFormat::Uuid4
+        }
+    };
+    let header = header.unwrap_or("X-Request-Id").to_string();
+    info!("Request ID header: {}, format: {:?}", header, format);
+    let _ = REQUEST_ID.set(RequestIdConfig { header, format });
+}
+
+pub fn request_id_header() -> &'static str {
+    REQUEST_ID.get().map_or("X-Request-Id", |c| c.header.as_str())
+}
+
+// ID of the incoming request if it comes from a trusted proxy and looks sane, a new one otherwise
+pub fn request_id(session: &Session) -> String {
+    if from_trusted_proxy(session) {
+        let incoming = session.req_header().headers.get(request_id_header()).and_then(|v| v.to_str().ok());
+        if let Some(id) = incoming.filter(|id| !id.is_empty() && id.len() <= MAX_INCOMING_LEN) {
+            return id.to_string();
+        }
+    }
+    generate(REQUEST_ID.get().map_or(IdFormat::Uuid4, |c| c.format))
+}
+
+pub fn generate(format: IdFormat) -> String {
+    let random: u128 = rand::rng().random();
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis()) & 0xFFFF_FFFF_FFFF;
+    match format {
+        IdFormat::Uuid4 => uuid(random, 4),
+        IdFormat::Uuid7 => uuid((millis << 80) | (random & ((1 << 80) - 1)), 7),
+        IdFormat::Ulid => ulid((millis << 80) | (random & ((1 << 80) - 1))),
+    }
+}
+
+fn uuid(bits: u128, version: u128) -> String {
+    let bits = (bits & !(0xF << 76)) | (version << 76);
+    let bits = (bits & !(0x3 << 62)) | (0x2 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        bits >> 96,
+        (bits >> 80) & 0xFFFF,
+        (bits >> 64) & 0xFFFF,
+        (bits >> 48) & 0xFFFF,
+        bits & 0xFFFF_FFFF_FFFF
+    )
+}
+
+// 26 characters of Crockford base32, the first one carries only the 3 top bits
+fn ulid(bits: u128) -> String {
+    (0..26).map(|i| ULID_ALPHABET[((bits >> (125 - 5 * i)) & 0x1F) as usize] as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid_bits(id: &str) -> u128 {
+        assert_eq!(id.len(), 36, "{}", id);
+        assert_eq!(id.match_indices('-').map(|(i, _)| i).collect::<Vec<_>>(), [8, 13, 18, 23], "{}", id);
+        u128::from_str_radix(&id.replace('-', ""), 16).unwrap()
+    }
+
+    #[test]
+    fn uuid_version_and_variant() {
+        for (format, version) in [(IdFormat::Uuid4, 4), (IdFormat::Uuid7, 7)] {
+            for _ in 0..100 {
+                let bits = uuid_bits(&generate(format));
+                assert_eq!((bits >> 76) & 0xF, version);
+                assert_eq!((bits >> 62) & 0x3, 0x2);
+            }
+        }
+        assert_eq!(uuid(u128::MAX, 4), "ffffffff-ffff-4fff-bfff-ffffffffffff");
+        assert_eq!(uuid(0, 7), "00000000-0000-7000-8000-000000000000");
+    }
+
+    #[test]
+    fn uuid7_starts_with_the_time() {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let stamp = uuid_bits(&generate(IdFormat::Uuid7)) >> 80;
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        assert!((before..=after).contains(&stamp), "{}", stamp);
+    }
+
+    #[test]
+    fn ulid_alphabet() {
+        for _ in 0..100 {
+            let id = generate(IdFormat::Ulid);
+            assert_eq!(id.len(), 26);
+            assert!(id.bytes().all(|c| ULID_ALPHABET.contains(&c)), "{}", id);
+            assert!(!id.contains(['I', 'L', 'O', 'U']), "{}", id);
+        }
+        assert_eq!(ulid(0), "00000000000000000000000000");
+        assert_eq!(ulid(u128::MAX), "7ZZZZZZZZZZZZZZZZZZZZZZZZZ");
+        assert_eq!(ulid(1), "00000000000000000000000001");
+    }
+}
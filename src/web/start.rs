@@ -4,17 +4,23 @@ use crate::tls::load;
 use crate::tls::load::CertificateConfig;
 use crate::utils::structs::Extraparams;
 use crate::utils::tools::*;
+use crate::web::cache::init_cache;
+use crate::web::forwarding::init_trusted_proxies;
 use crate::web::logging::init_access_log;
 use crate::web::proxyhttp::LB;
+use crate::web::proxyprotocol::ProxyProtocolApp;
+use crate::web::requestid::init_request_id;
 use arc_swap::ArcSwap;
 use dashmap::DashMap;
 use log::info;
-use pingora::tls::ssl::{SslAlert, SslRef};
+use pingora::tls::ssl::{SslAcceptor, SslAlert, SslFiletype, SslMethod, SslRef};
+use pingora_core::apps::ServerApp;
 use pingora_core::listeners::tls::TlsSettings;
 use pingora_core::listeners::TcpSocketOptions;
 use pingora_core::prelude::{background_service, Opt};
 use pingora_core::protocols::TcpKeepalive;
 use pingora_core::server::Server;
+use pingora_core::services::listening::Service;
 use privdrop::reexports::libc::SIGQUIT;
 use sd_notify::NotifyState;
 use signal_hook::{
@@ -26,6 +32,14 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::{fs, thread};
 
+fn add_tcp_listener<A: ServerApp + Send + Sync + 'static>(service: &mut Service<A>, address: &str, tcp_options: Option<TcpSocketOptions>) {
+    if let Some(tc) = tcp_options {
+        service.add_tcp_with_settings(address, tc);
+    } else {
+        service.add_tcp(address)
+    }
+}
+
 pub fn run() {
     // default_provider().install_default().expect("Failed to install rustls crypto provider");
     let parameters = Opt::parse_args();
@@ -47,6 +61,7 @@ pub fn run() {
         authentication: None,
         rate_limit: None,
         x4xx_limit: None,
+        host_patterns: Default::default(),
     }));
 
     let cfg = Arc::new(maincfg);
@@ -62,6 +77,9 @@ pub fn run() {
     };
     let al = cfg.access_log.clone().unwrap_or("none".to_string());
     init_access_log(al.as_str());
+    init_trusted_proxies(cfg.trusted_proxies.as_deref().unwrap_or_default());
+    init_request_id(cfg.request_id_header.as_deref(), cfg.request_id_format.as_deref());
+    init_cache(cfg.cache_size);
 
     let grade = cfg.proxy_tls_grade.clone().unwrap_or("medium".to_string());
     info!("TLS grade set to: [ {} ]", grade);
@@ -71,6 +89,7 @@ pub fn run() {
     let bind_address_tls = cfg.proxy_address_tls.clone();
 
     let mut proxy = pingora_proxy::http_proxy_service(&server.configuration, lb.clone());
+    let mut proxy_in_use = false;
 
     check_priv(bind_address_http.as_str());
 
@@ -115,14 +134,32 @@ pub fn run() {
         let new_certs = load::Certificates::new(&certificate_configs, grade.as_str());
         certs_for_watcher.store(Arc::new(new_certs.unwrap()));
 
-        let mut tls_settings =
-            TlsSettings::intermediate(&certs_for_callback.load().default_cert_path, &certs_for_callback.load().default_key_path).expect("unable to load or parse cert/key");
-
-        grades::set_tsl_grade(&mut tls_settings, grade.as_str());
-        tls_settings.set_servername_callback(move |ssl_ref: &mut SslRef, ssl_alert: &mut SslAlert| certs_for_callback.load().server_name_callback(ssl_ref, ssl_alert));
-        tls_settings.set_alpn_select_callback(grades::prefer_h2);
-
-        proxy.add_tls_with_settings(&bind_address_tls, tcp_options.clone(), tls_settings);
+        if cfg.proxy_protocol_tls.unwrap_or(false) {
+            // TLS starts after the PROXY protocol header, so the handshake is done by the listener app instead of pingora
+            let (cert, key) = (certs_for_callback.load().default_cert_path.clone(), certs_for_callback.load().default_key_path.clone());
+            let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).expect("unable to create TLS acceptor");
+            builder.set_certificate_chain_file(&cert).expect("unable to load certificate");
+            builder.set_private_key_file(&key, SslFiletype::PEM).expect("unable to load key");
+            grades::set_tsl_grade(&mut builder, grade.as_str());
+            builder.set_servername_callback(move |ssl_ref: &mut SslRef, ssl_alert: &mut SslAlert| certs_for_callback.load().server_name_callback(ssl_ref, ssl_alert));
+            builder.set_alpn_select_callback(grades::prefer_h2);
+
+            info!("Running TLS listener on :{} with PROXY protocol", bind_address_tls);
+            let app = ProxyProtocolApp::new(pingora_proxy::http_proxy(&server.configuration, lb.clone()), Some(builder.build()));
+            let mut service = Service::new("Aralez TLS PROXY protocol".to_string(), app);
+            add_tcp_listener(&mut service, &bind_address_tls, tcp_options.clone());
+            server.add_service(service);
+        } else {
+            let mut tls_settings =
+                TlsSettings::intermediate(&certs_for_callback.load().default_cert_path, &certs_for_callback.load().default_key_path).expect("unable to load or parse cert/key");
+
+            grades::set_tsl_grade(&mut tls_settings, grade.as_str());
+            tls_settings.set_servername_callback(move |ssl_ref: &mut SslRef, ssl_alert: &mut SslAlert| certs_for_callback.load().server_name_callback(ssl_ref, ssl_alert));
+            tls_settings.set_alpn_select_callback(grades::prefer_h2);
+
+            proxy.add_tls_with_settings(&bind_address_tls, tcp_options.clone(), tls_settings);
+            proxy_in_use = true;
+        }
 
         let certs_for_watcher = certificates.clone();
         thread::spawn(move || {
@@ -135,13 +172,20 @@ pub fn run() {
         });
     }
     info!("Running HTTP listener on :{}", bind_address_http);
-    if let Some(tc) = tcp_options {
-        proxy.add_tcp_with_settings(&bind_address_http, tc);
+    if cfg.proxy_protocol_http.unwrap_or(false) {
+        info!("PROXY protocol is enabled on HTTP listener");
+        let app = ProxyProtocolApp::new(pingora_proxy::http_proxy(&server.configuration, lb.clone()), None);
+        let mut service = Service::new("Aralez HTTP PROXY protocol".to_string(), app);
+        add_tcp_listener(&mut service, &bind_address_http, tcp_options);
+        server.add_service(service);
     } else {
-        proxy.add_tcp(&bind_address_http)
+        add_tcp_listener(&mut proxy, &bind_address_http, tcp_options);
+        proxy_in_use = true;
     }
 
-    server.add_service(proxy);
+    if proxy_in_use {
+        server.add_service(proxy);
+    }
     server.add_service(bg_srvc);
     thread::spawn(move || server.run_forever());
 
@@ -154,8 +198,8 @@ pub fn run() {
     if let Err(e) = write_pid_file(pf.as_str()) {
         panic!("Failed to write PID file: {} : {}", pf, e);
     }
-    let mut signals = Signals::new(&[SIGINT, SIGTERM, SIGQUIT]).unwrap();
-    for sig in signals.forever() {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGQUIT]).unwrap();
+    if let Some(sig) = signals.forever().next() {
         match sig {
             SIGINT => info!("SIGINT received! Exiting..."),
             SIGTERM => info!("SIGTERM received! Exiting..."),
@@ -165,6 +209,5 @@ pub fn run() {
             }
             _ => unreachable!(),
         }
-        break;
     }
 }
@@ -1,12 +1,13 @@
 use crate::utils::discovery::APIUpstreamProvider;
 use crate::utils::jwt::Claims;
-use crate::utils::metrics::{get_memory_usage, get_open_files, MEMORY_USAGE, OPEN_FILES};
+use crate::utils::metrics::{get_memory_usage, get_open_files, CACHE_SIZE, MEMORY_USAGE, OPEN_FILES};
 use crate::utils::structs::{Config, Configuration, UpstreamsDashMap};
 use crate::utils::tools::{upstreams_liveness_json, upstreams_to_json};
 use crate::web::acme::{acme_create, acme_order, http01_challenge};
+use crate::web::cache;
 use axum::body::Body;
 use axum::extract::{Query, State};
-use axum::http::{Response, StatusCode};
+use axum::http::{Method, Response, StatusCode};
 use axum::response::IntoResponse;
 use axum::routing::{any, get, post};
 use axum::{Json, Router};
@@ -63,11 +64,12 @@ pub async fn run_server(config: &APIUpstreamProvider, mut to_return: mpsc::Sende
         .route("/conf", post(conf))
         .route("/metrics", get(metrics))
         .route("/status", get(status))
+        .route("/cache", any(purge_cache))
         .with_state(app_state);
 
     let mut static_handle: Option<tokio::task::JoinHandle<()>> = None;
     if let (Some(address), Some(folder)) = (&config.file_server_address, &config.file_server_folder) {
-        let static_listen = port_is_available("File Server", &address).await;
+        let static_listen = port_is_available("File Server", address).await;
         let static_files = ServeDir::new(folder);
         let static_serve: Router = Router::new().fallback_service(static_files);
         // drop(tokio::spawn(async move { axum::serve(static_listen, static_serve).await.unwrap() }));
@@ -80,10 +82,9 @@ pub async fn run_server(config: &APIUpstreamProvider, mut to_return: mpsc::Sende
 
     let (tx, mut rx) = mpsc::channel(1);
     std::thread::spawn(move || {
-        let mut signals = Signals::new(&[SIGQUIT]).unwrap();
-        for sig in signals.forever() {
+        let mut signals = Signals::new([SIGQUIT]).unwrap();
+        if let Some(sig) = signals.forever().next() {
             tx.blocking_send(sig).unwrap();
-            break;
         }
     });
     rx.recv().await;
@@ -103,7 +104,7 @@ async fn conf(State(st): State<AppState>, Query(params): Query<HashMap<String, S
     let parsed = noyalib::from_str::<Config>(strcontent);
     match parsed {
         Ok(_) => {
-            if let Some(_) = params.get("save") {
+            if params.contains_key("save") {
                 drop(tokio::spawn(async move { apply_config(content.as_str(), st, true).await }));
             } else {
                 drop(tokio::spawn(async move { apply_config(content.as_str(), st, false).await }));
@@ -173,6 +174,7 @@ async fn jwt_gen(State(state): State<AppState>, Json(payload): Json<Claims>) ->
 async fn metrics() -> impl IntoResponse {
     MEMORY_USAGE.set(get_memory_usage() as i64);
     OPEN_FILES.set(get_open_files() as i64);
+    CACHE_SIZE.set(cache::store().weighted_size() as i64);
 
     let metric_families = gather();
     let encoder = TextEncoder::new();
@@ -191,6 +193,30 @@ async fn metrics() -> impl IntoResponse {
         .unwrap()
 }
 
+// PURGE or DELETE /cache?host=example.com&prefix=/images invalidates cached responses of the host under the path prefix
+async fn purge_cache(State(st): State<AppState>, method: Method, Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
+    if method.as_str() != "PURGE" && method != Method::DELETE {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::from("Use PURGE or DELETE\n"))
+            .unwrap();
+    }
+    // Purging is refused as long as no master_key is configured
+    if st.master_key.is_none() || params.get("key") != st.master_key.as_ref() {
+        return Response::builder().status(StatusCode::FORBIDDEN).body(Body::from("Access denied\n")).unwrap();
+    }
+    let Some(host) = params.get("host") else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Parameter host is required\n"))
+            .unwrap();
+    };
+    let prefix = params.get("prefix").map_or("/", |p| p.as_str());
+    let purged = cache::purge(host, prefix);
+    info!("Purged {} cached responses of {}{}", purged, host, prefix);
+    Response::builder().status(StatusCode::OK).body(Body::from(format!("Purged {} objects\n", purged))).unwrap()
+}
+
 #[allow(clippy::needless_return)]
 async fn status(State(st): State<AppState>, Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
     if params.contains_key("live") {